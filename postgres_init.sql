@@ -0,0 +1,33 @@
+CREATE TABLE state (
+    channel text NOT NULL,
+    payload text NOT NULL,
+
+    PRIMARY KEY(channel)
+);
+
+-- INSERT INTO state (channel, payload) VALUES ('13', '{"reward":"Lorem ipsum"}')
+
+CREATE TABLE messages (
+    id BIGSERIAL PRIMARY KEY,
+    room text NOT NULL,
+    author text NOT NULL,
+    created_at BIGINT NOT NULL,
+    body text NOT NULL
+);
+
+CREATE INDEX messages_room_id ON messages (room, id);
+
+-- changes of `state` rows are announced on `state_changes` channel, payload is name of the row
+CREATE FUNCTION notify_state_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('state_changes', OLD.channel);
+    ELSE
+        PERFORM pg_notify('state_changes', NEW.channel);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER state_change AFTER INSERT OR UPDATE OF payload OR DELETE ON state
+FOR EACH ROW EXECUTE PROCEDURE notify_state_change();
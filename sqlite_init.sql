@@ -1,4 +1,5 @@
-CREATE TABLE state (
+-- executed by the server on start, so missing tables and triggers are created in existing databases too
+CREATE TABLE IF NOT EXISTS state (
     channel text NOT NULL,
     payload text NOT NULL,
 
@@ -6,3 +7,35 @@ CREATE TABLE state (
 );
 
 -- INSERT INTO state (channel, payload) VALUES ('13', '{"reward":"Lorem ipsum"}')
+
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    room text NOT NULL,
+    author text NOT NULL,
+    created_at INTEGER NOT NULL,
+    body text NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS messages_room_id ON messages (room, id);
+
+-- changes of `state` rows, tailed by the server to update subscribers
+CREATE TABLE IF NOT EXISTS state_changelog (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    channel text NOT NULL,
+    changed_at INTEGER NOT NULL
+);
+
+CREATE TRIGGER IF NOT EXISTS state_insert AFTER INSERT ON state
+BEGIN
+    INSERT INTO state_changelog (channel, changed_at) VALUES (NEW.channel, strftime('%s', 'now'));
+END;
+
+CREATE TRIGGER IF NOT EXISTS state_update AFTER UPDATE OF payload ON state
+BEGIN
+    INSERT INTO state_changelog (channel, changed_at) VALUES (NEW.channel, strftime('%s', 'now'));
+END;
+
+CREATE TRIGGER IF NOT EXISTS state_delete AFTER DELETE ON state
+BEGIN
+    INSERT INTO state_changelog (channel, changed_at) VALUES (OLD.channel, strftime('%s', 'now'));
+END;
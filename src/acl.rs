@@ -0,0 +1,157 @@
+use crate::{auth::Identity, channel::ChannelPattern, config::AclConfig};
+
+/// Operation on channel which requires permission
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Subscribe,
+    Publish,
+}
+
+/// Authorization policy, decides who can access which channels
+pub trait Policy: Send + Sync {
+    /// Checks if client is allowed to perform operation on the channel
+    ///
+    /// # Arguments:
+    /// * `identity` - identity of client, `None` if client is not authenticated
+    /// * `permission` - requested operation
+    /// * `channel` - name of channel
+    fn allows(&self, identity: Option<&Identity>, permission: Permission, channel: &str) -> bool;
+}
+
+/// Policy granting everyone access to all channels
+pub struct AllowAll;
+
+impl Policy for AllowAll {
+    fn allows(
+        &self,
+        _identity: Option<&Identity>,
+        _permission: Permission,
+        _channel: &str,
+    ) -> bool {
+        true
+    }
+}
+
+/// Placeholder of channel patterns in access rules bound to subject of the client
+const SUBJECT_PLACEHOLDER: &str = "sub";
+
+/// Policy built from access rules, denies everything not allowed explicitly
+///
+/// `{sub}` placeholder in channel patterns matches only the subject of authenticated client, e.g.
+/// `user.{sub}.alerts`. Other placeholders match any segment.
+pub struct AclPolicy {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    subjects: Vec<String>,
+    roles: Vec<String>,
+    subscribe: Vec<ChannelPattern>,
+    publish: Vec<ChannelPattern>,
+}
+
+impl Rule {
+    /// Checks if rule applies to the client
+    fn applies_to(&self, identity: Option<&Identity>) -> bool {
+        if self.subjects.is_empty() && self.roles.is_empty() {
+            return true;
+        }
+
+        match identity {
+            Some(identity) => {
+                self.subjects.contains(&identity.subject)
+                    || identity.roles.iter().any(|role| self.roles.contains(role))
+            }
+            None => false,
+        }
+    }
+}
+
+impl AclPolicy {
+    /// Creates policy
+    ///
+    /// # Arguments:
+    /// * `config` - access rules
+    pub fn new(config: AclConfig) -> AclPolicy {
+        let patterns = |names: Vec<String>| names.into_iter().map(ChannelPattern::new).collect();
+
+        AclPolicy {
+            rules: config
+                .rules
+                .into_iter()
+                .map(|rule| Rule {
+                    subjects: rule.subjects,
+                    roles: rule.roles,
+                    subscribe: patterns(rule.subscribe),
+                    publish: patterns(rule.publish),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Policy for AclPolicy {
+    fn allows(&self, identity: Option<&Identity>, permission: Permission, channel: &str) -> bool {
+        let subject = identity.map(|identity| identity.subject.as_str());
+        let bindings = [(SUBJECT_PLACEHOLDER, subject)];
+
+        self.rules
+            .iter()
+            .filter(|rule| rule.applies_to(identity))
+            .flat_map(|rule| match permission {
+                Permission::Subscribe => rule.subscribe.iter(),
+                Permission::Publish => rule.publish.iter(),
+            })
+            .any(|pattern| pattern.matches_bound(channel, &bindings))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn acl_policy() {
+        let config: AclConfig = toml::from_str(
+            r#"
+            [[rule]]
+            roles = ["trader"]
+            subscribe = ["prices.*"]
+
+            [[rule]]
+            subjects = ["alice"]
+            publish = ["lobby"]
+
+            [[rule]]
+            subscribe = ["lobby", "user.{sub}.alerts"]
+            "#,
+        )
+        .unwrap();
+        let policy = AclPolicy::new(config);
+
+        let alice = Identity {
+            subject: "alice".into(),
+            roles: vec!["trader".into()],
+        };
+        let bob = Identity {
+            subject: "bob".into(),
+            roles: Vec::new(),
+        };
+
+        assert!(policy.allows(Some(&alice), Permission::Subscribe, "prices.eu"));
+        assert!(policy.allows(Some(&alice), Permission::Publish, "lobby"));
+        assert!(!policy.allows(Some(&alice), Permission::Publish, "prices.eu"));
+
+        assert!(policy.allows(Some(&bob), Permission::Subscribe, "lobby"));
+        assert!(!policy.allows(Some(&bob), Permission::Subscribe, "prices.eu"));
+        assert!(!policy.allows(Some(&bob), Permission::Publish, "lobby"));
+
+        assert!(policy.allows(None, Permission::Subscribe, "lobby"));
+        assert!(!policy.allows(None, Permission::Subscribe, "prices.eu"));
+
+        assert!(policy.allows(Some(&bob), Permission::Subscribe, "user.bob.alerts"));
+        assert!(!policy.allows(Some(&bob), Permission::Subscribe, "user.alice.alerts"));
+        assert!(!policy.allows(None, Permission::Subscribe, "user.bob.alerts"));
+    }
+}
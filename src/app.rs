@@ -1,33 +1,362 @@
-use crate::channel::{Reward, ThirteenChan};
-use crate::client;
-use crate::{broker::Broker, state::State, utils::spawn_and_log_err};
-use anyhow::{anyhow, Result};
-use sqlx::SqlitePool;
-use std::env;
+use crate::acl::{AclPolicy, AllowAll, Policy};
+use crate::auth::{Authenticator, JwtAuthenticator};
+use crate::backend::{MemoryBackend, PostgresBackend, SqliteBackend, StateBackend};
+use crate::broker::{Broker, Event};
+use crate::channel::{Channel, ChatChannel, Reward, RoomChannel, StateChannel};
+use crate::client::{self, ClientAddr};
+use crate::config::{AclConfig, AuthConfig, BackendKind, ChannelsConfig, Config, TlsConfig};
+use crate::listener::{AcceptFailure, Connection, Listener};
+use crate::outbox::OutboxConfig;
+use crate::{state::State, tls, utils::spawn_and_log_err};
+use anyhow::{anyhow, Context, Result};
+use futures::future::join_all;
+use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc::unbounded_channel;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{channel, Sender, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio_rustls::TlsAcceptor;
 
-pub async fn event_loop(mut listener: TcpListener) -> Result<()> {
-    let db_string = env::var("SQLITE_PATH").map_err(|_| anyhow!("Missing path to sqlite db"))?;
+/// Delay before accepting again when listener runs out of resources
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
-    let pool = SqlitePool::builder().max_size(5).build(&db_string).await?;
-    let state = State::new(pool);
+/// Runs the broker and accepts connections on all listeners until shutdown is requested
+///
+/// # Arguments:
+/// * `listeners` - bound listeners, at least one
+/// * `config` - validated server configuration
+/// * `config_path` - path to config file, channels are reloaded from it on SIGHUP
+/// * `shutdown` - future resolved when shutdown is requested
+pub async fn event_loop<F>(
+    listeners: Vec<Listener>,
+    config: Config,
+    config_path: Option<PathBuf>,
+    shutdown: F,
+) -> Result<()>
+where
+    F: Future<Output = ()>,
+{
+    if listeners.is_empty() {
+        return Err(anyhow!("No listeners configured"));
+    }
+
+    let limits = &config.limits;
+    let session_grace_period = Duration::from_secs(limits.session_grace_period);
+    let shutdown_timeout = Duration::from_secs(limits.shutdown_timeout);
+    let max_invalid_messages = limits.max_invalid_messages;
+    let extraction_timeout = Duration::from_millis(limits.extraction_timeout);
+    let cache_ttl = Duration::from_millis(limits.cache_ttl);
+    let outbox = OutboxConfig {
+        capacity: limits.outbox_capacity,
+        policy: limits.slow_consumer_policy,
+    };
+    let authenticator = match &config.auth.jwt_secret {
+        Some(secret) => {
+            Some(Arc::new(JwtAuthenticator::new(secret.as_bytes())) as Arc<dyn Authenticator>)
+        }
+        None => {
+            log::warn!("JWT secret is not set, clients are not authenticated");
+            None
+        }
+    };
+
+    let policy = policy(&config.auth)?;
+    let tls_acceptor = tls_acceptor(&config.tls)?;
 
-    let (broker_tx, broker_rx) = unbounded_channel();
+    let database = &config.database;
+    let backend: Arc<dyn StateBackend> = match (
+        database.backend_kind()?,
+        &database.postgres_url,
+        &database.sqlite_path,
+    ) {
+        (BackendKind::Postgres, Some(url), _) => {
+            Arc::new(PostgresBackend::connect(url, database.pool_size).await?)
+        }
+        (BackendKind::Sqlite, _, Some(path)) => Arc::new(
+            SqliteBackend::connect(
+                path,
+                database.pool_size,
+                Duration::from_millis(database.change_feed_interval),
+            )
+            .await?,
+        ),
+        (BackendKind::Memory, _, _) => {
+            log::warn!("State is kept in memory, nothing survives restart");
+            Arc::new(MemoryBackend::default())
+        }
+        (kind, _, _) => return Err(anyhow!("{:?} database is not configured", kind)),
+    };
+
+    let state = State::new(Arc::clone(&backend));
+    let max_history = config.limits.max_history;
+    let configured_channels = configured_channels(&state, config.channels, max_history).await?;
+
+    let (broker_tx, broker_rx) = channel(config.limits.broker_queue_size);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
     let mut broker = Broker::new(broker_rx, state);
+    broker
+        .session_grace_period(session_grace_period)
+        .extraction_timeout(extraction_timeout)
+        .cache_ttl(cache_ttl)
+        .compression_threshold(config.compression.threshold)
+        .policy(policy);
 
     broker.add_channel(Arc::new(Reward {}));
-    broker.add_channel(Arc::new(ThirteenChan {}));
+    broker.add_channel(Arc::new(ChatChannel::new("chat")));
+
+    broker.reload_channels(configured_channels).await;
+
+    spawn_and_log_err(reload_on_hangup(
+        config_path,
+        State::new(Arc::clone(&backend)),
+        max_history,
+        broker.channel_reloads(),
+    ));
 
     log::debug!("Enter event_loop");
     // borrow the broker for 'static and spawn its worker future
-    spawn_and_log_err(async move { broker.worker().await });
+    let broker_handle = spawn_and_log_err(async move { broker.worker(shutdown_rx).await });
+
+    let acceptor = Acceptor {
+        broker_tx,
+        max_invalid_messages,
+        authenticator,
+        tls_acceptor,
+        outbox,
+    };
+
+    // one acceptor per listener, all of them feed the same broker
+    let acceptors = listeners
+        .into_iter()
+        .map(|listener| Box::pin(acceptor.clone().run(listener)));
+
+    // asynchronously accept incoming connections until shutdown is requested or all of listeners
+    // fail, a failed listener is closed while others keep serving
+    tokio::select! {
+        _ = join_all(acceptors) => log::error!("All listeners stopped accepting connections"),
+        _ = shutdown => log::info!("Shutdown requested"),
+    }
+
+    // stop accepting and let broker close client connections
+    drop(acceptor);
+    shutdown_tx.send(()).ok();
 
-    // asynchronously accept incoming TCP streams
-    while let Ok((stream, _)) = listener.accept().await {
-        spawn_and_log_err(client::handle_connection(stream, broker_tx.clone()));
+    let graceful_shutdown = async {
+        if let Err(e) = broker_handle.await {
+            log::error!("Broker worker failed: {}", e);
+        }
+
+        backend.close().await;
+    };
+
+    match tokio::time::timeout(shutdown_timeout, graceful_shutdown).await {
+        Ok(()) => log::info!("Shutdown completed"),
+        Err(_) => log::warn!("Shutdown deadline of {:?} exceeded", shutdown_timeout),
     }
 
     Ok(())
 }
+
+/// Accepts connections on listener and spawns their handlers
+#[derive(Clone)]
+struct Acceptor {
+    broker_tx: Sender<Event>,
+    max_invalid_messages: usize,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    tls_acceptor: Option<TlsAcceptor>,
+    outbox: OutboxConfig,
+}
+
+impl Acceptor {
+    /// Accepts connections until listener fails for good, the listener is closed then
+    ///
+    /// Failures of single connections are skipped, exhausted resources (e.g. file descriptors)
+    /// are waited out.
+    ///
+    /// # Arguments:
+    /// * `listener` - bound listener
+    async fn run(self, mut listener: Listener) {
+        loop {
+            match listener.accept().await {
+                Ok((connection, addr)) => self.serve(connection, addr),
+                Err(e) => match AcceptFailure::of(&e) {
+                    AcceptFailure::Connection => {
+                        log::debug!("Failed to accept connection: {}", e);
+                    }
+                    AcceptFailure::Resources => {
+                        log::error!("Failed to accept connection: {}", e);
+                        tokio::time::delay_for(ACCEPT_RETRY_DELAY).await;
+                    }
+                    AcceptFailure::Fatal => {
+                        log::error!("Listener {} failed: {}", listener, e);
+                        return;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Spawns connection handler, TCP connections are wrapped with TLS if configured
+    ///
+    /// # Arguments:
+    /// * `connection` - accepted connection
+    /// * `addr` - client's address
+    fn serve(&self, connection: Connection, addr: ClientAddr) {
+        let broker_tx = self.broker_tx.clone();
+        let max_invalid_messages = self.max_invalid_messages;
+        let authenticator = self.authenticator.clone();
+        let outbox = self.outbox;
+
+        match (connection, self.tls_acceptor.clone()) {
+            (Connection::Tcp(stream), Some(tls_acceptor)) => spawn_and_log_err(async move {
+                let stream = tls_acceptor
+                    .accept(stream)
+                    .await
+                    .with_context(|| format!("TLS handshake with {} failed", addr))?;
+
+                client::handle_connection(
+                    stream,
+                    addr,
+                    broker_tx,
+                    max_invalid_messages,
+                    authenticator,
+                    outbox,
+                )
+                .await
+            }),
+            (Connection::Tcp(stream), None) => spawn_and_log_err(client::handle_connection(
+                stream,
+                addr,
+                broker_tx,
+                max_invalid_messages,
+                authenticator,
+                outbox,
+            )),
+            (Connection::Unix(stream), _) => spawn_and_log_err(client::handle_connection(
+                stream,
+                addr,
+                broker_tx,
+                max_invalid_messages,
+                authenticator,
+                outbox,
+            )),
+        };
+    }
+}
+
+/// Checks configuration including files it points to, without starting the server
+///
+/// # Arguments:
+/// * `config` - validated server configuration
+pub fn check_config(config: &Config) -> Result<()> {
+    policy(&config.auth)?;
+    tls_acceptor(&config.tls)?;
+
+    Ok(())
+}
+
+/// Creates authorization policy, everything is allowed unless access rules are configured
+///
+/// # Arguments:
+/// * `auth` - authentication and access control configuration
+fn policy(auth: &AuthConfig) -> Result<Arc<dyn Policy>> {
+    let policy: Arc<dyn Policy> = match &auth.acl_config {
+        Some(path) => Arc::new(AclPolicy::new(AclConfig::from_file(path)?)),
+        None => Arc::new(AllowAll),
+    };
+
+    Ok(policy)
+}
+
+/// Loads TLS certificate and private key, if configured
+///
+/// # Arguments:
+/// * `tls` - TLS configuration
+fn tls_acceptor(tls: &TlsConfig) -> Result<Option<TlsAcceptor>> {
+    match (&tls.cert_path, &tls.key_path) {
+        (Some(cert_path), Some(key_path)) => Ok(Some(tls::acceptor(cert_path, key_path)?)),
+        (None, None) => Ok(None),
+        _ => Err(anyhow!("Both TLS certificate and key have to be set")),
+    }
+}
+
+/// Reloads channels from configuration whenever process receives SIGHUP
+///
+/// Other settings require restart. Invalid configuration is reported and ignored.
+///
+/// # Arguments:
+/// * `config_path` - path to config file
+/// * `state` - application state
+/// * `max_history` - maximal number of room messages replayed on subscribe, set at startup
+/// * `reloads` - sender of reloaded channels, see `Broker::reload_channels`
+async fn reload_on_hangup(
+    config_path: Option<PathBuf>,
+    state: State,
+    max_history: u32,
+    reloads: UnboundedSender<Vec<Arc<dyn Channel>>>,
+) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    while hangup.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading channels");
+
+        let channels = match Config::load(config_path.as_deref()) {
+            Ok(config) => configured_channels(&state, config.channels, max_history).await,
+            Err(e) => Err(e),
+        };
+
+        match channels {
+            Ok(channels) => {
+                if reloads.send(channels).is_err() {
+                    // broker is gone
+                    break;
+                }
+            }
+            Err(e) => log::error!("Failed to reload channels: {:#}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates channels serving stored payloads and chat rooms
+///
+/// Payloads stored in state backend are discovered if enabled (the default).
+///
+/// # Arguments:
+/// * `state` - application state
+/// * `config` - channels configuration
+/// * `max_history` - maximal number of room messages replayed on subscribe
+async fn configured_channels(
+    state: &State,
+    config: ChannelsConfig,
+    max_history: u32,
+) -> Result<Vec<Arc<dyn Channel>>> {
+    let mut state_channels: Vec<StateChannel> =
+        config.state.into_iter().map(StateChannel::new).collect();
+
+    if config.discover {
+        for channel in StateChannel::discover(state).await? {
+            if !state_channels.iter().any(|c| c.name() == channel.name()) {
+                state_channels.push(channel);
+            }
+        }
+    }
+
+    let mut channels: Vec<Arc<dyn Channel>> = Vec::new();
+    channels.extend(
+        state_channels
+            .into_iter()
+            .map(|channel| Arc::new(channel) as Arc<dyn Channel>),
+    );
+    channels.extend(
+        config
+            .rooms
+            .into_iter()
+            .map(|room| Arc::new(RoomChannel::new(room, max_history)) as Arc<dyn Channel>),
+    );
+
+    Ok(channels)
+}
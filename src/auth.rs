@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Result};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tungstenite::handshake::server::Request;
+
+/// Identity of authenticated client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Unique id of the client, e.g. user id
+    pub subject: String,
+
+    /// Roles granted to the client
+    pub roles: Vec<String>,
+}
+
+/// Verifies bearer tokens presented during websocket handshake
+pub trait Authenticator: Send + Sync {
+    /// Verifies token and returns identity of its owner
+    ///
+    /// # Arguments:
+    /// * `token` - bearer token
+    fn authenticate(&self, token: &str) -> Result<Identity>;
+}
+
+/// Claims of tokens accepted by `JwtAuthenticator`
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+/// Authenticator of JWT tokens signed with HMAC SHA-256 (HS256)
+///
+/// Tokens must carry `sub` and `exp` claims, optional `roles` claim lists roles of the client
+pub struct JwtAuthenticator {
+    key: DecodingKey<'static>,
+    validation: Validation,
+}
+
+impl JwtAuthenticator {
+    /// Creates authenticator
+    ///
+    /// # Arguments:
+    /// * `secret` - secret shared with token issuer
+    pub fn new(secret: &[u8]) -> JwtAuthenticator {
+        JwtAuthenticator {
+            key: DecodingKey::from_secret(secret).into_static(),
+            validation: Validation::new(Algorithm::HS256),
+        }
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate(&self, token: &str) -> Result<Identity> {
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+            .map_err(|e| anyhow!("Invalid token: {}", e))?
+            .claims;
+
+        Ok(Identity {
+            subject: claims.sub,
+            roles: claims.roles,
+        })
+    }
+}
+
+/// Finds bearer token in upgrade request
+///
+/// Token is read from `Authorization: Bearer <token>` header or from `token` query parameter,
+/// since browsers can't set headers of websocket requests.
+///
+/// # Arguments:
+/// * `request` - websocket upgrade request
+pub fn bearer_token(request: &Request) -> Option<&str> {
+    let from_header = request
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| strip_scheme(value, "Bearer "));
+
+    from_header.or_else(|| {
+        request
+            .uri()
+            .query()?
+            .split('&')
+            .filter_map(|param| {
+                let mut parts = param.splitn(2, '=');
+                Some((parts.next()?, parts.next()?))
+            })
+            .find(|(key, _)| *key == "token")
+            .map(|(_, value)| value)
+    })
+}
+
+/// Strips case insensitive authorization scheme from header value
+fn strip_scheme<'a>(value: &'a str, scheme: &str) -> Option<&'a str> {
+    if value.len() > scheme.len() && value[..scheme.len()].eq_ignore_ascii_case(scheme) {
+        Some(value[scheme.len()..].trim())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn token(secret: &[u8], exp_offset: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let claims = json!({"sub": "alice", "roles": ["admin"], "exp": now + exp_offset});
+
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn jwt_authenticate() {
+        let authenticator = JwtAuthenticator::new(b"secret");
+
+        assert_eq!(
+            authenticator.authenticate(&token(b"secret", 60)).unwrap(),
+            Identity {
+                subject: "alice".into(),
+                roles: vec!["admin".into()],
+            }
+        );
+
+        assert!(authenticator.authenticate(&token(b"other", 60)).is_err());
+        assert!(authenticator.authenticate(&token(b"secret", -120)).is_err());
+        assert!(authenticator.authenticate("garbage").is_err());
+    }
+
+    #[test]
+    fn bearer_token_lookup() {
+        let request = Request::builder()
+            .uri("/?token=abc")
+            .header("Authorization", "bearer xyz")
+            .body(())
+            .unwrap();
+        assert_eq!(bearer_token(&request), Some("xyz"));
+
+        let request = Request::builder()
+            .uri("/ws?lang=en&token=abc")
+            .body(())
+            .unwrap();
+        assert_eq!(bearer_token(&request), Some("abc"));
+
+        let request = Request::builder()
+            .uri("/")
+            .header("Authorization", "Basic abc")
+            .body(())
+            .unwrap();
+        assert_eq!(bearer_token(&request), None);
+    }
+}
@@ -0,0 +1,169 @@
+use super::{StateBackend, StoredMessage, Watchers};
+use crate::frame::HistoryQuery;
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+#[derive(Debug, Default)]
+struct Rooms {
+    last_id: i64,
+    messages: HashMap<String, Vec<StoredMessage>>,
+}
+
+/// Backend keeping everything in memory, nothing survives restart
+///
+/// Useful for tests and demos, which don't need a database file.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    payloads: Mutex<HashMap<String, Value>>,
+    rooms: Mutex<Rooms>,
+    watchers: Watchers,
+}
+
+#[async_trait::async_trait]
+impl StateBackend for MemoryBackend {
+    async fn get(&self, channel: &str) -> Result<Option<Value>> {
+        Ok(self.payloads.lock().unwrap().get(channel).cloned())
+    }
+
+    async fn put(&self, channel: &str, payload: &Value) -> Result<()> {
+        self.payloads
+            .lock()
+            .unwrap()
+            .insert(channel.to_string(), payload.clone());
+        self.watchers.notify(channel);
+
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut channels: Vec<String> = self.payloads.lock().unwrap().keys().cloned().collect();
+        channels.sort();
+
+        Ok(channels)
+    }
+
+    fn watch(&self, channel: &str) -> broadcast::Receiver<()> {
+        self.watchers.watch(channel)
+    }
+
+    async fn append_message(
+        &self,
+        room: &str,
+        author: &str,
+        created_at: i64,
+        body: &Value,
+    ) -> Result<i64> {
+        let mut rooms = self.rooms.lock().unwrap();
+
+        // ids are unique across rooms, like the ones of `messages` table
+        rooms.last_id += 1;
+        let message = StoredMessage {
+            id: rooms.last_id,
+            author: author.to_string(),
+            created_at,
+            body: body.clone(),
+        };
+
+        rooms
+            .messages
+            .entry(room.to_string())
+            .or_default()
+            .push(message);
+
+        Ok(rooms.last_id)
+    }
+
+    async fn messages(
+        &self,
+        room: &str,
+        query: HistoryQuery,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>> {
+        let rooms = self.rooms.lock().unwrap();
+        let messages = match rooms.messages.get(room) {
+            Some(messages) => messages.as_slice(),
+            None => return Ok(Vec::new()),
+        };
+
+        let messages = match query {
+            HistoryQuery::Last(count) => {
+                let skip = messages.len().saturating_sub(count.min(limit) as usize);
+                messages[skip..].to_vec()
+            }
+            HistoryQuery::Since(id) => {
+                let start = messages
+                    .iter()
+                    .position(|message| message.id > id)
+                    .unwrap_or(messages.len());
+                let skip = messages.len().saturating_sub(limit as usize).max(start);
+                messages[skip..].to_vec()
+            }
+        };
+
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn payloads() {
+        let backend = MemoryBackend::default();
+        let mut updates = backend.watch("13");
+
+        assert_eq!(backend.get("13").await.unwrap(), None);
+
+        backend.put("13", &json!({"reward": 1})).await.unwrap();
+        backend.put("prices", &json!([])).await.unwrap();
+
+        assert_eq!(backend.get("13").await.unwrap(), Some(json!({"reward": 1})));
+        assert_eq!(backend.list().await.unwrap(), vec!["13", "prices"]);
+        assert!(updates.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn messages() {
+        let backend = MemoryBackend::default();
+
+        for body in 1..=3 {
+            backend
+                .append_message("lobby", "alice", 0, &json!(body))
+                .await
+                .unwrap();
+        }
+        backend
+            .append_message("kitchen", "bob", 0, &json!(4))
+            .await
+            .unwrap();
+
+        let bodies = |messages: Vec<StoredMessage>| -> Vec<Value> {
+            messages.into_iter().map(|message| message.body).collect()
+        };
+
+        let last = backend.messages("lobby", HistoryQuery::Last(2), 10).await;
+        assert_eq!(bodies(last.unwrap()), vec![json!(2), json!(3)]);
+
+        let since = backend.messages("lobby", HistoryQuery::Since(1), 10).await;
+        assert_eq!(bodies(since.unwrap()), vec![json!(2), json!(3)]);
+
+        let kitchen = backend
+            .messages("kitchen", HistoryQuery::Last(10), 10)
+            .await;
+        assert_eq!(kitchen.unwrap()[0].id, 4);
+
+        let empty = backend.messages("attic", HistoryQuery::Last(10), 10).await;
+        assert!(empty.unwrap().is_empty());
+
+        let limited = backend.messages("lobby", HistoryQuery::Last(10), 1).await;
+        assert_eq!(bodies(limited.unwrap()), vec![json!(3)]);
+
+        let limited = backend.messages("lobby", HistoryQuery::Since(0), 2).await;
+        assert_eq!(bodies(limited.unwrap()), vec![json!(2), json!(3)]);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::frame::HistoryQuery;
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+mod memory;
+mod postgres;
+mod sqlite;
+
+pub use memory::MemoryBackend;
+pub use postgres::PostgresBackend;
+pub use sqlite::SqliteBackend;
+
+/// Message stored in chat room
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub author: String,
+    pub created_at: i64,
+    pub body: Value,
+}
+
+/// Storage of channel payloads and chat room messages
+#[async_trait::async_trait]
+pub trait StateBackend: Send + Sync {
+    /// Returns payload of channel, `None` if channel has no payload
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    async fn get(&self, channel: &str) -> Result<Option<Value>>;
+
+    /// Stores payload of channel, watchers of the channel are notified
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    /// * `payload` - new payload
+    async fn put(&self, channel: &str, payload: &Value) -> Result<()>;
+
+    /// Returns names of all channels with payload
+    async fn list(&self) -> Result<Vec<String>>;
+
+    /// Returns receiver notified whenever payload of channel changes
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    fn watch(&self, channel: &str) -> broadcast::Receiver<()>;
+
+    /// Appends message to chat room, returns id of the message
+    ///
+    /// # Arguments:
+    /// * `room` - name of room
+    /// * `author` - author of the message
+    /// * `created_at` - unix timestamp in milliseconds
+    /// * `body` - message published by client
+    async fn append_message(
+        &self,
+        room: &str,
+        author: &str,
+        created_at: i64,
+        body: &Value,
+    ) -> Result<i64>;
+
+    /// Returns messages of chat room ordered by id
+    ///
+    /// At most `limit` latest messages are returned, so replayed history always ends with the most
+    /// recent message and continues seamlessly with live ones.
+    ///
+    /// # Arguments:
+    /// * `room` - name of room
+    /// * `query` - range of requested messages
+    /// * `limit` - maximal number of returned messages
+    async fn messages(
+        &self,
+        room: &str,
+        query: HistoryQuery,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>>;
+
+    /// Stops background tasks and releases connections
+    async fn close(&self) {}
+}
+
+/// Change notifiers of channels, shared by backends
+#[derive(Debug, Default)]
+pub struct Watchers {
+    senders: Mutex<HashMap<String, broadcast::Sender<()>>>,
+}
+
+impl Watchers {
+    /// Returns receiver of change notifications
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    pub fn watch(&self, channel: &str) -> broadcast::Receiver<()> {
+        self.senders
+            .lock()
+            .unwrap()
+            .entry(channel.to_string())
+            .or_insert_with(|| broadcast::channel(1).0)
+            .subscribe()
+    }
+
+    /// Notifies watchers that payload of channel changed
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    pub fn notify(&self, channel: &str) {
+        if let Some(sender) = self.senders.lock().unwrap().get(channel) {
+            // no receivers - nobody observes the channel anymore
+            sender.send(()).ok();
+        }
+    }
+
+    /// Notifies watchers of all channels, used when changes might have been missed
+    pub fn notify_all(&self) {
+        for sender in self.senders.lock().unwrap().values() {
+            sender.send(()).ok();
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use super::{StateBackend, StoredMessage, Watchers};
+use crate::frame::HistoryQuery;
+use crate::utils::spawn_and_log_err;
+use anyhow::Result;
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, oneshot};
+use tokio::task::JoinHandle;
+
+/// How long entries are kept in `state_changelog` table
+const CHANGELOG_RETENTION: Duration = Duration::from_secs(3600);
+
+/// How often old entries are removed from `state_changelog` table
+const CHANGELOG_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Delay before retrying failed read of `state_changelog` table
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+type MessageRow = (i64, String, i64, String);
+
+/// Schema created on connect, databases made with older `sqlite_init.sql` get missing parts
+const SCHEMA: &str = include_str!("../../sqlite_init.sql");
+
+/// Splits SQL script into statements, trigger bodies are kept whole
+///
+/// # Arguments:
+/// * `script` - statements terminated by `;`, `--` comments on separate lines
+fn statements(script: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut statement = String::new();
+
+    for line in script.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("--") {
+            continue;
+        }
+
+        statement.push_str(line);
+        statement.push('\n');
+
+        let in_trigger = statement.starts_with("CREATE TRIGGER");
+        if line.ends_with(';') && (!in_trigger || line == "END;") {
+            statements.push(statement.trim_end().to_string());
+            statement.clear();
+        }
+    }
+
+    statements
+}
+
+/// Backend storing payloads in `state` table and room messages in `messages` table
+///
+/// SQL triggers (see `sqlite_init.sql`) record every change of `state` rows in `state_changelog`
+/// table. A change feed task tails the changelog and notifies watchers of changed rows, so writes
+/// of external processes reach subscribers right away.
+pub struct SqliteBackend {
+    pool: SqlitePool,
+    watchers: Arc<Watchers>,
+    change_feed: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+}
+
+impl SqliteBackend {
+    /// Opens database, creates missing tables and triggers and starts its change feed
+    ///
+    /// # Arguments:
+    /// * `path` - path to sqlite database
+    /// * `pool_size` - maximal number of connections
+    /// * `poll_interval` - how often changelog is checked
+    pub async fn connect(
+        path: &str,
+        pool_size: u32,
+        poll_interval: Duration,
+    ) -> Result<SqliteBackend> {
+        let pool = SqlitePool::builder()
+            .max_size(pool_size)
+            .build(path)
+            .await?;
+        let watchers = Arc::new(Watchers::default());
+
+        for statement in statements(SCHEMA) {
+            sqlx::query(&statement).execute(&pool).await?;
+        }
+
+        // changes recorded before start are skipped, channels read the current state anyway
+        let (last_id,): (i64,) = sqlx::query_as("SELECT COALESCE(MAX(id), 0) FROM state_changelog")
+            .fetch_one(&pool)
+            .await?;
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let handle = spawn_and_log_err(change_feed(
+            pool.clone(),
+            Arc::clone(&watchers),
+            poll_interval,
+            last_id,
+            shutdown_rx,
+        ));
+
+        Ok(SqliteBackend {
+            pool,
+            watchers,
+            change_feed: Mutex::new(Some((shutdown_tx, handle))),
+        })
+    }
+
+    /// Converts stored message
+    fn message((id, author, created_at, body): MessageRow) -> Result<StoredMessage> {
+        Ok(StoredMessage {
+            id,
+            author,
+            created_at,
+            body: serde_json::from_str(&body)?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl StateBackend for SqliteBackend {
+    async fn get(&self, channel: &str) -> Result<Option<Value>> {
+        let row: Option<(Option<String>,)> =
+            sqlx::query_as("SELECT payload FROM state WHERE channel = ?")
+                .bind(channel)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        match row.and_then(|row| row.0) {
+            Some(payload) => Ok(Some(serde_json::from_str(&payload)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put(&self, channel: &str, payload: &Value) -> Result<()> {
+        // watchers are notified by the change feed, like on writes of other processes
+        sqlx::query(
+            "INSERT INTO state (channel, payload) VALUES (?, ?) \
+             ON CONFLICT (channel) DO UPDATE SET payload = excluded.payload",
+        )
+        .bind(channel)
+        .bind(payload.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let rows: Vec<(String,)> = sqlx::query_as("SELECT channel FROM state ORDER BY channel")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|(channel,)| channel).collect())
+    }
+
+    fn watch(&self, channel: &str) -> broadcast::Receiver<()> {
+        self.watchers.watch(channel)
+    }
+
+    async fn append_message(
+        &self,
+        room: &str,
+        author: &str,
+        created_at: i64,
+        body: &Value,
+    ) -> Result<i64> {
+        // last_insert_rowid is bound to connection
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query("INSERT INTO messages (room, author, created_at, body) VALUES (?, ?, ?, ?)")
+            .bind(room)
+            .bind(author)
+            .bind(created_at)
+            .bind(body.to_string())
+            .execute(&mut conn)
+            .await?;
+
+        let (id,): (i64,) = sqlx::query_as("SELECT last_insert_rowid()")
+            .fetch_one(&mut conn)
+            .await?;
+
+        Ok(id)
+    }
+
+    async fn messages(
+        &self,
+        room: &str,
+        query: HistoryQuery,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>> {
+        let rows: Vec<MessageRow> = match query {
+            HistoryQuery::Last(count) => {
+                let mut rows: Vec<MessageRow> = sqlx::query_as(
+                    "SELECT id, author, created_at, body FROM messages \
+                     WHERE room = ? ORDER BY id DESC LIMIT ?",
+                )
+                .bind(room)
+                .bind(i64::from(count.min(limit)))
+                .fetch_all(&self.pool)
+                .await?;
+
+                rows.reverse();
+                rows
+            }
+            HistoryQuery::Since(id) => {
+                let mut rows: Vec<MessageRow> = sqlx::query_as(
+                    "SELECT id, author, created_at, body FROM messages \
+                     WHERE room = ? AND id > ? ORDER BY id DESC LIMIT ?",
+                )
+                .bind(room)
+                .bind(id)
+                .bind(i64::from(limit))
+                .fetch_all(&self.pool)
+                .await?;
+
+                rows.reverse();
+                rows
+            }
+        };
+
+        rows.into_iter().map(Self::message).collect()
+    }
+
+    async fn close(&self) {
+        let change_feed = self.change_feed.lock().unwrap().take();
+
+        if let Some((shutdown_tx, handle)) = change_feed {
+            shutdown_tx.send(()).ok();
+
+            if let Err(e) = handle.await {
+                log::error!("Change feed failed: {}", e);
+            }
+        }
+
+        self.pool.close().await;
+    }
+}
+
+/// Tails changelog until shutdown, notifying watchers of changed rows
+///
+/// Database errors don't stop the feed, reading is retried after a delay. Once it recovers, all
+/// watchers are notified, since changes might have been pruned in the meantime.
+///
+/// # Arguments:
+/// * `pool` - sqlite connection pool
+/// * `watchers` - change notifiers of channels
+/// * `poll_interval` - how often changelog is checked
+/// * `last_id` - id of the last changelog entry already seen
+/// * `shutdown` - shutdown signal
+async fn change_feed(
+    pool: SqlitePool,
+    watchers: Arc<Watchers>,
+    poll_interval: Duration,
+    mut last_id: i64,
+    mut shutdown: oneshot::Receiver<()>,
+) -> Result<()> {
+    let mut poll = tokio::time::interval(poll_interval);
+    let mut pruned_at = Instant::now();
+    let mut failed = false;
+
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {}
+            _ = &mut shutdown => break,
+        }
+
+        match read_changes(&pool, &watchers, &mut last_id).await {
+            Ok(()) if failed => {
+                log::info!("Change feed recovered");
+                failed = false;
+                watchers.notify_all();
+            }
+            Ok(()) => {}
+            Err(e) => {
+                log::warn!("Failed to read state changelog: {}", e);
+                failed = true;
+
+                tokio::select! {
+                    _ = tokio::time::delay_for(RETRY_DELAY) => continue,
+                    _ = &mut shutdown => break,
+                }
+            }
+        }
+
+        if pruned_at.elapsed() >= CHANGELOG_PRUNE_INTERVAL {
+            if let Err(e) = prune(&pool).await {
+                log::warn!("Failed to prune state changelog: {}", e);
+            }
+            pruned_at = Instant::now();
+        }
+    }
+
+    Ok(())
+}
+
+/// Notifies watchers of rows changed since the last seen changelog entry
+///
+/// # Arguments:
+/// * `pool` - sqlite connection pool
+/// * `watchers` - change notifiers of channels
+/// * `last_id` - id of the last changelog entry already seen, advanced past read entries
+async fn read_changes(pool: &SqlitePool, watchers: &Watchers, last_id: &mut i64) -> Result<()> {
+    let changes: Vec<(i64, String)> =
+        sqlx::query_as("SELECT id, channel FROM state_changelog WHERE id > ? ORDER BY id")
+            .bind(*last_id)
+            .fetch_all(pool)
+            .await?;
+
+    if let Some((id, _)) = changes.last() {
+        *last_id = *id;
+    }
+
+    let changed: HashSet<String> = changes.into_iter().map(|(_, channel)| channel).collect();
+
+    for channel in changed {
+        log::debug!("State of channel {} changed", channel);
+        watchers.notify(&channel);
+    }
+
+    Ok(())
+}
+
+/// Removes changelog entries older than retention period
+///
+/// # Arguments:
+/// * `pool` - sqlite connection pool
+async fn prune(pool: &SqlitePool) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let threshold = now.saturating_sub(CHANGELOG_RETENTION).as_secs() as i64;
+
+    sqlx::query("DELETE FROM state_changelog WHERE changed_at < ?")
+        .bind(threshold)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn schema() {
+        let statements = statements(SCHEMA);
+
+        assert_eq!(statements.len(), 7);
+        assert!(statements[0].starts_with("CREATE TABLE IF NOT EXISTS state ("));
+        assert_eq!(
+            statements[4],
+            "CREATE TRIGGER IF NOT EXISTS state_insert AFTER INSERT ON state\n\
+             BEGIN\n\
+             INSERT INTO state_changelog (channel, changed_at) VALUES (NEW.channel, strftime('%s', 'now'));\n\
+             END;"
+        );
+    }
+
+    #[tokio::test]
+    async fn change_feed() {
+        // every connection gets its own in-memory database
+        let backend = SqliteBackend::connect("sqlite::memory:", 1, Duration::from_millis(10))
+            .await
+            .unwrap();
+        let mut updates = backend.watch("13");
+        let timeout = Duration::from_secs(5);
+
+        backend.put("13", &json!({"reward": 1})).await.unwrap();
+        assert!(tokio::time::timeout(timeout, updates.recv()).await.is_ok());
+
+        // write of other process
+        sqlx::query("UPDATE state SET payload = ? WHERE channel = ?")
+            .bind(json!({"reward": 2}).to_string())
+            .bind("13")
+            .execute(&backend.pool)
+            .await
+            .unwrap();
+        assert!(tokio::time::timeout(timeout, updates.recv()).await.is_ok());
+
+        let payload = backend.get("13").await.unwrap();
+        assert_eq!(payload, Some(json!({"reward": 2})));
+
+        backend.close().await;
+    }
+}
@@ -1,28 +1,39 @@
 use crate::{
-    channel::Channel,
-    client::Client,
-    frame::{Frame, FrameData},
+    acl::{AllowAll, Permission, Policy},
+    cache::{CacheStats, ExtractionCache, ExtractionResult},
+    channel::{Channel, ChannelPattern, Publish},
+    client::{Client, ClientAddr, Session},
+    frame::{DeltaEncoding, Frame, FrameData, HistoryQuery, DEFAULT_COMPRESSION_THRESHOLD},
     state::State,
-    utils::create_json_snapshot,
+    utils::spawn_and_log_err,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::future::{abortable, join_all, AbortHandle};
 use futures::stream::StreamExt;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use std::{collections::HashMap, net::SocketAddr};
-use tokio::sync::mpsc::UnboundedReceiver;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::mpsc::{unbounded_channel, Receiver, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tungstenite::protocol::frame::coding::CloseCode;
 
 /// Events occuring on client's websocket
 #[derive(Debug)]
 pub struct Event {
-    addr: SocketAddr,
+    addr: ClientAddr,
     data: EventData,
 }
 
 /// Specializations of events
 #[derive(Debug)]
 pub enum EventData {
-    NewClient(Client),
+    NewClient(Box<Client>),
     ClientFrame(Frame),
+    Reply(Frame),
+    Close { code: CloseCode, reason: String },
     Disconnect,
 }
 
@@ -32,10 +43,10 @@ impl Event {
     /// # Arguments:
     /// * `addr` - socket
     /// * `client` - client info
-    pub fn new_client(addr: SocketAddr, client: Client) -> Event {
+    pub fn new_client(addr: ClientAddr, client: Client) -> Event {
         Event {
             addr,
-            data: EventData::NewClient(client),
+            data: EventData::NewClient(Box::new(client)),
         }
     }
 
@@ -44,18 +55,46 @@ impl Event {
     /// # Arguments:
     /// * `addr` - socket
     /// * `client_frame` - frame unpacked from client's message
-    pub fn new_client_frame(addr: SocketAddr, client_frame: Frame) -> Event {
+    pub fn new_client_frame(addr: ClientAddr, client_frame: Frame) -> Event {
         Event {
             addr,
             data: EventData::ClientFrame(client_frame),
         }
     }
 
+    /// Creates "reply" event - a frame which should be sent to client as is
+    ///
+    /// # Arguments:
+    /// * `addr` - socket
+    /// * `frame` - frame to be sent
+    pub fn reply(addr: ClientAddr, frame: Frame) -> Event {
+        Event {
+            addr,
+            data: EventData::Reply(frame),
+        }
+    }
+
+    /// Creates "close" event - requests closing client's connection
+    ///
+    /// # Arguments:
+    /// * `addr` - socket
+    /// * `code` - close status code
+    /// * `reason` - human readable reason
+    pub fn close<S: Into<String>>(addr: ClientAddr, code: CloseCode, reason: S) -> Event {
+        Event {
+            addr,
+            data: EventData::Close {
+                code,
+                reason: reason.into(),
+            },
+        }
+    }
+
     /// Creates "disconnect" event
     ///
     /// # Arguments:
     /// * `addr` - socket
-    pub fn disconnect(addr: SocketAddr) -> Event {
+    pub fn disconnect(addr: ClientAddr) -> Event {
         Event {
             addr,
             data: EventData::Disconnect,
@@ -71,19 +110,89 @@ impl Event {
 /// Channel subscribtion events
 #[derive(Debug, Clone, Copy)]
 enum ManageSubscription {
-    Subscribe,
+    Subscribe {
+        encoding: Option<DeltaEncoding>,
+        history: Option<HistoryQuery>,
+    },
     Unsubscribe,
 }
 
-type ClientMap = HashMap<SocketAddr, Client>;
+type ClientMap = HashMap<ClientAddr, Client>;
 type ChannelMap = HashMap<String, Arc<dyn Channel>>;
+type SessionMap = HashMap<String, (Instant, Session)>;
+
+/// How often expired sessions are dropped
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long channels can take to extract their data by default
+const DEFAULT_EXTRACTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long extracted channel data is shared between clients by default
+const DEFAULT_CACHE_TTL: Duration = Duration::from_millis(100);
+
+/// How often extraction cache counters are logged
+const CACHE_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Prefix of subscriptions to channel presence, e.g. `presence:chat`
+const PRESENCE_PREFIX: &str = "presence:";
+
+/// Maximal length of display name
+const MAX_DISPLAY_NAME_LENGTH: usize = 64;
+
+/// Reason of close frame sent on shutdown
+const SHUTDOWN_REASON: &str = "Server is shutting down";
+
+/// Maximal number of data requests queued behind the one being served
+const MAX_PENDING_REQUESTS: usize = 16;
+
+/// Reason of err frame sent to subscribers of removed channel
+const CHANNEL_REMOVED_REASON: &str = "Channel was removed";
+
+/// Recipients of extracted channel data
+#[derive(Debug)]
+enum ExtractionTarget {
+    /// Client which requested data with the frame
+    Client { addr: ClientAddr, frame: Frame },
+
+    /// Subscribers of changed channels
+    Subscribers,
+}
+
+/// Channel data extracted off the broker loop
+///
+/// Extractions are numbered in order of start, so results finishing out of order can't overwrite
+/// data of later extractions.
+#[derive(Debug)]
+struct Extraction {
+    target: ExtractionTarget,
+    seq: u64,
+    results: Vec<(String, ExtractionResult)>,
+}
 
 /// Event dispatcher
 pub struct Broker {
-    rx: UnboundedReceiver<Event>,
-    state: State,
+    rx: Receiver<Event>,
+    updates_tx: UnboundedSender<String>,
+    updates_rx: UnboundedReceiver<String>,
+    update_tasks: HashMap<String, AbortHandle>,
+    reload_tx: UnboundedSender<Vec<Arc<dyn Channel>>>,
+    reload_rx: UnboundedReceiver<Vec<Arc<dyn Channel>>>,
+    configured_channels: HashSet<String>,
+    extracted_tx: UnboundedSender<Extraction>,
+    extracted_rx: UnboundedReceiver<Extraction>,
+    extraction_seq: u64,
+    updating_channels: HashSet<String>,
+    outdated_channels: HashSet<String>,
+    state: Arc<State>,
+    cache: Arc<ExtractionCache>,
     client_map: ClientMap,
     channel_map: ChannelMap,
+    session_map: SessionMap,
+    session_grace_period: Duration,
+    extraction_timeout: Duration,
+    compression_threshold: usize,
+    policy: Arc<dyn Policy>,
+    shutting_down: bool,
 }
 
 impl Broker {
@@ -92,34 +201,317 @@ impl Broker {
     /// # Arguments:
     /// * `rx` - reading half of event mpsc channel
     /// * `state` - a pointer to application state
-    pub fn new(rx: UnboundedReceiver<Event>, state: State) -> Broker {
+    pub fn new(rx: Receiver<Event>, state: State) -> Broker {
+        let (updates_tx, updates_rx) = unbounded_channel();
+        let (extracted_tx, extracted_rx) = unbounded_channel();
+        let (reload_tx, reload_rx) = unbounded_channel();
+
         Broker {
             rx,
-            state,
+            updates_tx,
+            updates_rx,
+            update_tasks: HashMap::new(),
+            reload_tx,
+            reload_rx,
+            configured_channels: HashSet::new(),
+            extracted_tx,
+            extracted_rx,
+            extraction_seq: 0,
+            updating_channels: HashSet::new(),
+            outdated_channels: HashSet::new(),
+            state: Arc::new(state),
+            cache: Arc::new(ExtractionCache::new(DEFAULT_CACHE_TTL)),
             client_map: HashMap::new(),
             channel_map: HashMap::new(),
+            session_map: HashMap::new(),
+            session_grace_period: Duration::from_secs(0),
+            extraction_timeout: DEFAULT_EXTRACTION_TIMEOUT,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            policy: Arc::new(AllowAll),
+            shutting_down: false,
         }
     }
 
+    /// Sets how long sessions of disconnected clients are kept for resumption
+    ///
+    /// # Arguments:
+    /// * `period` - grace period, zero disables resumption
+    pub fn session_grace_period(&mut self, period: Duration) -> &mut Self {
+        self.session_grace_period = period;
+        self
+    }
+
+    /// Sets how long channels can take to extract their data
+    ///
+    /// # Arguments:
+    /// * `timeout` - extraction timeout of single channel
+    pub fn extraction_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.extraction_timeout = timeout;
+        self
+    }
+
+    /// Sets how long extracted channel data is shared between clients
+    ///
+    /// # Arguments:
+    /// * `ttl` - lifetime of cached data, zero shares only extractions in progress
+    pub fn cache_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.cache = Arc::new(ExtractionCache::new(ttl));
+        self
+    }
+
+    /// Sets length of payload, above which data frames are compressed
+    ///
+    /// # Arguments:
+    /// * `threshold` - length of payload in bytes
+    pub fn compression_threshold(&mut self, threshold: usize) -> &mut Self {
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// Returns sender of reloaded channel configuration, see `reload_channels`
+    pub fn channel_reloads(&self) -> UnboundedSender<Vec<Arc<dyn Channel>>> {
+        self.reload_tx.clone()
+    }
+
+    /// Returns hit and miss counters of extraction cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Sets authorization policy consulted on subscribe and publish
+    ///
+    /// # Arguments:
+    /// * `policy` - authorization policy, everything is allowed by default
+    pub fn policy(&mut self, policy: Arc<dyn Policy>) -> &mut Self {
+        self.policy = policy;
+        self
+    }
+
     /// Adds channel to broker
     ///
+    /// Channels exposing change notifications get a forwarding task, which wakes up the broker
+    /// whenever channel's data changes.
+    ///
     /// # Arguments:
     /// * `channel` - a pointer to channel
     pub fn add_channel(&mut self, channel: Arc<dyn Channel>) -> &mut Self {
-        self.channel_map.insert(channel.name().to_string(), channel);
+        let name = channel.name().to_string();
+
+        if let Some(mut updates) = channel.updates(&self.state) {
+            let updates_tx = self.updates_tx.clone();
+            let channel_name = name.clone();
+
+            let (forward, handle) = abortable(async move {
+                // lagging behind is fine - subscribers receive the latest state anyway
+                while let Ok(()) | Err(RecvError::Lagged(_)) = updates.recv().await {
+                    updates_tx.send(channel_name.clone())?;
+                }
+
+                Ok::<_, anyhow::Error>(())
+            });
+
+            // aborted forwarding of removed channel is not an error
+            spawn_and_log_err(async move { forward.await.unwrap_or(Ok(())) });
+
+            if let Some(previous) = self.update_tasks.insert(name.clone(), handle) {
+                previous.abort();
+            }
+        }
+
+        // clients subscribed to matching patterns observe the channel right away
+        for client in self.client_map.values_mut() {
+            let allowed = self
+                .policy
+                .allows(client.identity(), Permission::Subscribe, &name);
+
+            if allowed && client.subscribe_if_matches(&channel) {
+                log::info!(
+                    "{} subscribed to channel {} by pattern",
+                    client.addr(),
+                    name
+                );
+            }
+        }
+
+        // parked sessions are checked against the policy on resume
+        for (_, session) in self.session_map.values_mut() {
+            session.subscribe_if_matches(&channel);
+        }
+
+        self.channel_map.insert(name, channel);
         self
     }
 
+    /// Removes channel from broker
+    ///
+    /// Subscribers and presence observers of the channel receive `410` err frame, channel's data
+    /// is dropped from the last message of subscribers. Connections stay open.
+    ///
+    /// # Arguments:
+    /// * `name` - name of channel
+    pub async fn remove_channel(&mut self, name: &str) {
+        let channel = match self.channel_map.remove(name) {
+            Some(channel) => channel,
+            None => return,
+        };
+
+        if let Some(handle) = self.update_tasks.remove(name) {
+            handle.abort();
+        }
+        self.cache.invalidate(name);
+
+        for client in self.client_map.values_mut() {
+            let subscribed = client.channels().contains(&channel);
+
+            if !subscribed && !client.watches_presence(name) {
+                continue;
+            }
+
+            log::info!(
+                "{} unsubscribed from removed channel {}",
+                client.addr(),
+                name
+            );
+            client.retain_channels(|channel| channel != name);
+
+            let notice = Frame::create_channel_err_frame(0, name, 410, CHANNEL_REMOVED_REASON);
+            if let Err(e) = client.send_msg(notice).await {
+                log::error!("An error occurred while sending message: {}", e);
+            }
+
+            let mut payload = client.last_message().cloned().unwrap_or_else(|| json!({}));
+            let had_data = match payload.as_object_mut() {
+                Some(data) => data.remove(name).is_some(),
+                None => false,
+            };
+
+            if subscribed && had_data {
+                let (delta, reset) = client.create_delta(payload);
+                let response = Frame::create_push_frame(
+                    client.encoding(),
+                    client.version(),
+                    reset,
+                    delta,
+                    client.compression_threshold(),
+                );
+
+                if let Err(e) = client.send_msg(response).await {
+                    log::error!("An error occurred while sending message: {}", e);
+                }
+            }
+        }
+
+        for (_, session) in self.session_map.values_mut() {
+            session.retain_channels(|channel| channel != name);
+        }
+    }
+
+    /// Replaces channels registered from configuration
+    ///
+    /// Channels missing in the new configuration are removed (see `remove_channel`), new ones are
+    /// added. Channels present in both keep their subscribers, unless their kind changed - they
+    /// are replaced then. Channels registered by other means are never touched.
+    ///
+    /// # Arguments:
+    /// * `channels` - channels of the new configuration
+    pub async fn reload_channels(&mut self, channels: Vec<Arc<dyn Channel>>) {
+        let mut configured = HashSet::new();
+
+        for channel in channels {
+            let name = channel.name().to_string();
+
+            if self.configured_channels.contains(&name) {
+                let same_kind = match self.channel_map.get(&name) {
+                    Some(current) => current.kind() == channel.kind(),
+                    None => false,
+                };
+
+                if !same_kind {
+                    log::info!("Replacing channel {}", name);
+                    self.remove_channel(&name).await;
+                    self.add_channel(channel);
+                }
+
+                configured.insert(name);
+            } else if self.channel_map.contains_key(&name) {
+                log::warn!("Channel {} is already registered", name);
+            } else {
+                log::info!("Registering channel {}", name);
+                self.add_channel(channel);
+                configured.insert(name);
+            }
+        }
+
+        let removed: Vec<String> = self
+            .configured_channels
+            .difference(&configured)
+            .cloned()
+            .collect();
+
+        for name in removed {
+            log::info!("Removing channel {}", name);
+            self.remove_channel(&name).await;
+        }
+
+        self.configured_channels = configured;
+    }
+
     /// Worker future, performs broker logic
-    pub async fn worker(&mut self) -> Result<()> {
-        while let Some(event) = self.rx.next().await {
-            self.handle_event(event).await;
-            log::info!("Connected clients: {}", self.client_map.len());
+    ///
+    /// On shutdown signal every client receives a close frame. Worker keeps draining events
+    /// until all connections are gone and the event channel is closed.
+    ///
+    /// # Arguments:
+    /// * `shutdown` - shutdown signal, dropped sender is treated as shutdown as well
+    pub async fn worker(&mut self, mut shutdown: oneshot::Receiver<()>) -> Result<()> {
+        let mut session_sweep = tokio::time::interval(SESSION_SWEEP_INTERVAL);
+        let mut cache_stats = tokio::time::interval(CACHE_STATS_INTERVAL);
+
+        loop {
+            tokio::select! {
+                event = self.rx.next() => match event {
+                    Some(event) => {
+                        self.handle_event(event).await;
+                        log::info!("Connected clients: {}", self.client_map.len());
+                    }
+                    // acceptor and all connections are gone
+                    None => break,
+                },
+                Some(channel) = self.updates_rx.next() => self.request_channel_update(&channel),
+                Some(channels) = self.reload_rx.next() => self.reload_channels(channels).await,
+                Some(extraction) = self.extracted_rx.next() => {
+                    if let Err(e) = self.handle_extraction(extraction).await {
+                        log::error!("Failed to deliver channel data: {}", e);
+                    }
+                }
+                _ = session_sweep.tick() => self.drop_expired_sessions(),
+                _ = cache_stats.tick() => {
+                    let stats = self.cache_stats();
+                    log::info!("Extraction cache: {} hits, {} misses", stats.hits, stats.misses);
+                }
+                _ = &mut shutdown, if !self.shutting_down => {
+                    self.shutting_down = true;
+                    self.close_clients().await;
+                }
+            }
         }
 
+        log::debug!("Broker worker finished");
+
         Ok(())
     }
 
+    /// Initiates close handshake with every connected client
+    async fn close_clients(&mut self) {
+        log::info!("Closing {} client connections", self.client_map.len());
+
+        for client in self.client_map.values_mut() {
+            if let Err(e) = client.close(CloseCode::Away, SHUTDOWN_REASON).await {
+                log::error!("Failed to close connection of {}: {}", client.addr(), e);
+            }
+        }
+    }
+
     /// Handles incoming event
     ///
     /// # Arguments:
@@ -130,22 +522,65 @@ impl Broker {
         let addr = event.addr;
 
         match event.event_data() {
-            NewClient(client) => {
-                self.client_map.insert(addr, client);
+            NewClient(mut client) => {
+                client.set_compression_threshold(self.compression_threshold);
+
+                let send_msg_result = if self.shutting_down {
+                    client.close(CloseCode::Away, SHUTDOWN_REASON).await
+                } else {
+                    let welcome = Frame::create_welcome_frame(client.token());
+                    client.send_msg(welcome).await
+                };
+
+                if let Err(e) = send_msg_result {
+                    log::error!("An error occurred while sending message: {}", e);
+                }
+
+                self.client_map.insert(addr, *client);
+            }
+            Reply(frame) => {
+                if let Err(e) = self.send_to_client(addr, frame).await {
+                    log::error!("An error occurred while sending message: {}", e);
+                }
+            }
+            Close { code, reason } => {
+                let close_result = match Self::get_client(&mut self.client_map, addr) {
+                    Ok(client) => client.close(code, &reason).await,
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = close_result {
+                    log::error!("Failed to close connection of {}: {}", addr, e);
+                }
             }
             Disconnect => {
-                self.client_map.remove(&addr);
+                if let Some(client) = self.client_map.remove(&addr) {
+                    let member = client.member_name();
+                    let name = client.display_name().map(str::to_string);
+                    let channels = client.channel_names();
+
+                    self.park_session(client);
+                    self.broadcast_presence(&channels, &member, name.as_deref(), false)
+                        .await;
+                }
             }
             ClientFrame(frame) => {
                 log::info!("Received frame: {:?}", frame);
 
                 let send_msg_result = match frame.data() {
-                    FrameData::Subscribe { channels } => {
+                    FrameData::Subscribe {
+                        channels,
+                        encoding,
+                        history,
+                    } => {
                         self.manage_subscription(
                             addr,
                             &frame,
-                            &*channels,
-                            ManageSubscription::Subscribe,
+                            channels,
+                            ManageSubscription::Subscribe {
+                                encoding: *encoding,
+                                history: *history,
+                            },
                         )
                         .await
                     }
@@ -153,13 +588,24 @@ impl Broker {
                         self.manage_subscription(
                             addr,
                             &frame,
-                            &*channels,
+                            channels,
                             ManageSubscription::Unsubscribe,
                         )
                         .await
                     }
                     FrameData::Ready => self.fetch_data_from_channels(addr, &frame).await,
-                    _ => unreachable!(),
+                    FrameData::Resume { session, version } => {
+                        self.resume_session(addr, &frame, session, *version).await
+                    }
+                    FrameData::Resync => self.resync(addr, &frame).await,
+                    FrameData::Publish { channel, payload } => {
+                        self.publish(addr, &frame, channel, payload.clone()).await
+                    }
+                    FrameData::Identify { name } => self.identify(addr, &frame, name).await,
+                    _ => {
+                        let resp = Frame::create_err_frame(&frame, 501, "Unsupported frame type");
+                        self.send_to_client(addr, resp).await
+                    }
                 };
 
                 if let Err(e) = send_msg_result {
@@ -171,6 +617,11 @@ impl Broker {
 
     /// Updates client's subscription state
     ///
+    /// Subscriber can request replay of channel messages, which are sent right after the response.
+    ///
+    /// Subscription to `presence:<channel>` delivers current members of the channel followed by
+    /// their joins and leaves. Observers of the affected channels are notified about the change.
+    ///
     /// # Arguments:
     /// * `addr` - socket
     /// * `frame` - subscribe/unsubscribe frame received from client
@@ -178,58 +629,164 @@ impl Broker {
     /// * `mode` - subscribe or unsubscribe
     async fn manage_subscription(
         &mut self,
-        addr: SocketAddr,
+        addr: ClientAddr,
         frame: &Frame,
         channels: &[String],
         mode: ManageSubscription,
     ) -> Result<()> {
-        let client = Self::get_client(&mut self.client_map, addr);
+        let client = Self::get_client(&mut self.client_map, addr)?;
         let chan_map = &self.channel_map;
+        let policy = &self.policy;
+        let identity = client.identity().cloned();
+        let can_subscribe =
+            |name: &str| policy.allows(identity.as_ref(), Permission::Subscribe, name);
 
-        // find channels that are not registered within broker but requested by client
-        let (requested_channels, not_registered): (Vec<&str>, Vec<&str>) = channels
-            .iter()
-            .map(|s| s.as_str())
-            .partition(|chan| chan_map.contains_key(*chan));
+        // find channels that are not registered within broker but requested by client,
+        // patterns are resolved to all registered channels matching them and allowed by policy
+        let mut requested_channels = Vec::new();
+        let mut requested_patterns = Vec::new();
+        let mut requested_presence = Vec::new();
+        let mut not_registered = Vec::new();
+
+        for chan in channels.iter().map(|s| s.as_str()) {
+            if let Some(target) = chan.strip_prefix(PRESENCE_PREFIX) {
+                if chan_map.contains_key(target) {
+                    requested_presence.push(target.to_string());
+                } else {
+                    not_registered.push(chan);
+                }
+                continue;
+            }
+
+            if ChannelPattern::is_pattern(chan) {
+                requested_patterns.push(ChannelPattern::new(chan));
+                continue;
+            }
 
-        let resp = if not_registered.is_empty() {
-            requested_channels.into_iter().for_each(|chan| {
-                let channel_ptr = Arc::clone(&chan_map.get(chan).unwrap());
+            match chan_map.get(chan) {
+                Some(channel_ptr) => requested_channels.push(Arc::clone(channel_ptr)),
+                None => not_registered.push(chan),
+            }
+        }
+
+        // explicitly requested channels have to be allowed, unsubscribing is always possible
+        let forbidden: Vec<&str> = match mode {
+            ManageSubscription::Subscribe { .. } => requested_channels
+                .iter()
+                .map(|channel| channel.name())
+                .chain(requested_presence.iter().map(|channel| channel.as_str()))
+                .filter(|name| !can_subscribe(name))
+                .collect(),
+            ManageSubscription::Unsubscribe => Vec::new(),
+        };
+
+        let accepted = not_registered.is_empty() && forbidden.is_empty();
+
+        let mut subscribed = Vec::new();
+        let mut joined = Vec::new();
+        let mut left = Vec::new();
+
+        let resp = if accepted {
+            let action = match mode {
+                ManageSubscription::Subscribe { .. } => "subscribed to",
+                ManageSubscription::Unsubscribe => "unsubscribed from",
+            };
+
+            for pattern in requested_patterns {
+                log::info!("{} {} pattern {}", addr, action, pattern);
 
                 match mode {
-                    ManageSubscription::Subscribe => {
-                        client.subscribe(channel_ptr);
+                    ManageSubscription::Subscribe { .. } => {
+                        let matching = chan_map.values().filter(|channel| {
+                            pattern.matches(channel.name()) && can_subscribe(channel.name())
+                        });
+
+                        for channel_ptr in matching {
+                            let name = channel_ptr.name().to_string();
+                            subscribed.push(Arc::clone(channel_ptr));
+
+                            if client.subscribe_matching(Arc::clone(channel_ptr)) {
+                                log::info!("{} {} channel {} by pattern", addr, action, name);
+                                joined.push(name);
+                            }
+                        }
+
+                        client.subscribe_pattern(pattern);
                     }
                     ManageSubscription::Unsubscribe => {
-                        client.unsubscribe(channel_ptr);
+                        left.extend(client.unsubscribe_pattern(&pattern));
                     }
                 }
+            }
 
-                log::info!(
-                    "{} {} channel {}",
-                    addr,
-                    match mode {
-                        ManageSubscription::Subscribe => "subscribed to",
-                        ManageSubscription::Unsubscribe => "unsubscribed from",
-                    },
-                    chan
-                );
-            });
+            for channel_ptr in requested_channels {
+                log::info!("{} {} channel {}", addr, action, channel_ptr.name());
 
-            Frame::create_ok_frame(&frame)
+                match mode {
+                    ManageSubscription::Subscribe { .. } => {
+                        let name = channel_ptr.name().to_string();
+                        subscribed.push(Arc::clone(&channel_ptr));
+
+                        if client.subscribe(channel_ptr) {
+                            joined.push(name);
+                        }
+                    }
+                    ManageSubscription::Unsubscribe => {
+                        let name = channel_ptr.name().to_string();
+
+                        if client.unsubscribe(channel_ptr) {
+                            left.push(name);
+                        }
+                    }
+                }
+            }
+
+            for channel in requested_presence.iter() {
+                log::info!("{} {} presence of channel {}", addr, action, channel);
+
+                match mode {
+                    ManageSubscription::Subscribe { .. } => client.watch_presence(channel),
+                    ManageSubscription::Unsubscribe => client.unwatch_presence(channel),
+                }
+            }
+
+            if let ManageSubscription::Subscribe {
+                encoding: Some(encoding),
+                ..
+            } = mode
+            {
+                client.set_encoding(encoding);
+            }
+
+            Frame::create_ok_frame(frame)
+        } else if !forbidden.is_empty() {
+            log::info!(
+                "Client {} is not allowed to subscribe to following channels: {:?}",
+                addr,
+                forbidden
+            );
+
+            Frame::create_err_frame(
+                frame,
+                403,
+                format!(
+                    "Not allowed to subscribe to following channels: {}",
+                    forbidden.join(",")
+                ),
+            )
         } else {
             log::info!(
                 "Client {} attempted to {} following channels: {:?}",
                 addr,
                 match mode {
-                    ManageSubscription::Subscribe => "subscribe to",
+                    ManageSubscription::Subscribe { .. } => "subscribe to",
                     ManageSubscription::Unsubscribe => "unsubscribe from",
                 },
                 not_registered
             );
 
             Frame::create_err_frame(
-                &frame,
+                frame,
                 404,
                 format!(
                     "Following channels were not found: {}",
@@ -238,51 +795,892 @@ impl Broker {
             )
         };
 
-        client.send_msg(resp).await
+        client.send_msg(resp).await?;
+
+        // replay past messages before any live message reaches the client
+        if let ManageSubscription::Subscribe {
+            history: Some(query),
+            ..
+        } = mode
+        {
+            for channel in subscribed {
+                match channel.history(&self.state, client.identity(), query).await {
+                    Ok(messages) => {
+                        for message in messages {
+                            let message_frame =
+                                Frame::create_message_frame(channel.name(), message);
+                            client.send_msg(message_frame).await?;
+                        }
+                    }
+                    Err(e) => {
+                        log::error!(
+                            "Failed to fetch history of channel {}: {}",
+                            channel.name(),
+                            e
+                        );
+
+                        let error = Frame::create_channel_err_frame(
+                            frame.cseq(),
+                            channel.name(),
+                            500,
+                            format!("Failed to fetch history: {}", e),
+                        );
+                        client.send_msg(error).await?;
+                    }
+                }
+            }
+        }
+
+        let member = client.member_name();
+        let name = client.display_name().map(str::to_string);
+        self.broadcast_presence(&joined, &member, name.as_deref(), true)
+            .await;
+        self.broadcast_presence(&left, &member, name.as_deref(), false)
+            .await;
+
+        // a rejected request doesn't change observed presence
+        if let (ManageSubscription::Subscribe { .. }, true) = (mode, accepted) {
+            for channel in requested_presence {
+                let members = self.members(&channel);
+                let presence_frame = Frame::create_presence_frame(&channel, members);
+                self.send_to_client(addr, presence_frame).await?;
+            }
+        }
+
+        Ok(())
     }
 
-    /// Fetches live data for client.
+    /// Sets display name of the client
     ///
-    /// Locks the state in `read` mode, extracts data from channels observed by the client.
-    /// Sends only incremental diff of observed state.
+    /// Observers of client's channels see the client leaving under the old name and joining
+    /// under the new one. Authenticated clients keep their subject as member name, only the
+    /// display name changes.
+    ///
+    /// # Arguments:
+    /// * `addr` - socket
+    /// * `frame` - identify frame received from client
+    /// * `name` - chosen display name
+    async fn identify(&mut self, addr: ClientAddr, frame: &Frame, name: &str) -> Result<()> {
+        let name = name.trim();
+
+        if name.is_empty() || name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+            let resp = Frame::create_err_frame(
+                frame,
+                400,
+                format!(
+                    "Display name should have 1 to {} characters",
+                    MAX_DISPLAY_NAME_LENGTH
+                ),
+            );
+            return self.send_to_client(addr, resp).await;
+        }
+
+        let client = Self::get_client(&mut self.client_map, addr)?;
+        let old_member = client.member_name();
+        let old_name = client.display_name().map(str::to_string);
+        let channels = client.channel_names();
+
+        log::info!("{} identified as {}", addr, name);
+        client.set_display_name(name.to_string());
+        let member = client.member_name();
+
+        if old_member != member || old_name.as_deref() != Some(name) {
+            self.broadcast_presence(&channels, &old_member, old_name.as_deref(), false)
+                .await;
+            self.broadcast_presence(&channels, &member, Some(name), true)
+                .await;
+        }
+
+        self.send_to_client(addr, Frame::create_ok_frame(frame))
+            .await
+    }
+
+    /// Returns sorted names of clients subscribed to the channel
+    ///
+    /// # Arguments:
+    /// * `name` - name of channel
+    fn members(&self, name: &str) -> Vec<String> {
+        let members: BTreeSet<String> = self
+            .client_map
+            .values()
+            .filter(|client| client.channels().iter().any(|c| c.name() == name))
+            .map(|client| client.member_name())
+            .collect();
+
+        members.into_iter().collect()
+    }
+
+    /// Notifies presence observers about member joining or leaving channels
+    ///
+    /// # Arguments:
+    /// * `channels` - names of channels
+    /// * `member` - name of member
+    /// * `name` - display name of member
+    /// * `joined` - member joined (`true`) or left (`false`) the channels
+    async fn broadcast_presence(
+        &mut self,
+        channels: &[String],
+        member: &str,
+        name: Option<&str>,
+        joined: bool,
+    ) {
+        for client in self.client_map.values_mut() {
+            let observed: Vec<&String> = channels
+                .iter()
+                .filter(|channel| client.watches_presence(channel))
+                .collect();
+
+            for channel in observed {
+                let frame = Frame::create_presence_change_frame(channel, member, name, joined);
+
+                if let Err(e) = client.send_msg(frame).await {
+                    log::error!("An error occurred while sending message: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Requests live data for client.
+    ///
+    /// Data of channels observed by the client is extracted off the broker loop, the response is
+    /// sent once extraction completes. Requests of single client are served one at a time.
     ///
     /// # Arguments:
     /// * `addr` - socket
     /// * `frame` - frame received from client
-    /// * `channels` - a list of channels to sub/unsub
-    /// * `mode` - subscribe or unsubscribe
-    async fn fetch_data_from_channels(&mut self, addr: SocketAddr, frame: &Frame) -> Result<()> {
-        use std::collections::BTreeMap;
-        let client = Self::get_client(&mut self.client_map, addr);
+    async fn fetch_data_from_channels(&mut self, addr: ClientAddr, frame: &Frame) -> Result<()> {
+        let client = Self::get_client(&mut self.client_map, addr)?;
+
+        if client.pending_requests() >= MAX_PENDING_REQUESTS {
+            log::warn!("{} sent too many data requests", addr);
+
+            let resp = Frame::create_err_frame(frame, 429, "Too many pending data requests");
+            return client.send_msg(resp).await;
+        }
+
+        if !client.begin_request(frame) {
+            return Ok(());
+        }
+
+        let channels = client.channels().iter().cloned().collect();
+
+        self.spawn_extraction(
+            channels,
+            ExtractionTarget::Client {
+                addr,
+                frame: frame.clone(),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Extracts data from channels concurrently in a separate task
+    ///
+    /// Every channel has `extraction_timeout` to deliver its data. Extractions are shared with
+    /// other clients through the cache. Results are passed back to the broker loop.
+    ///
+    /// # Arguments:
+    /// * `channels` - channels to extract data from
+    /// * `target` - recipients of extracted data
+    fn spawn_extraction(&mut self, channels: Vec<Arc<dyn Channel>>, target: ExtractionTarget) {
+        self.extraction_seq += 1;
+
+        let seq = self.extraction_seq;
+        let state = Arc::clone(&self.state);
+        let cache = Arc::clone(&self.cache);
+        let extracted_tx = self.extracted_tx.clone();
+        let extraction_timeout = self.extraction_timeout;
+
+        tokio::spawn(async move {
+            let extractions = channels.into_iter().map(|channel| {
+                let name = channel.name().to_string();
+                let state = Arc::clone(&state);
+                let cache = &cache;
+
+                let extract = async move {
+                    let extracted =
+                        tokio::time::timeout(extraction_timeout, channel.extract_data(&state))
+                            .await;
+
+                    match extracted {
+                        Ok(Ok(data)) => Ok(data),
+                        Ok(Err(e)) => Err(format!("Failed to extract data: {}", e)),
+                        Err(_) => Err(format!(
+                            "Failed to extract data: timed out after {:?}",
+                            extraction_timeout
+                        )),
+                    }
+                };
+
+                async move {
+                    let result = cache.get_or_extract(&name, extract).await;
+                    (name, result)
+                }
+            });
+
+            let results = join_all(extractions).await;
+
+            // broker is gone if shutdown completed in the meantime
+            extracted_tx
+                .send(Extraction {
+                    target,
+                    seq,
+                    results,
+                })
+                .ok();
+        });
+    }
+
+    /// Delivers extracted data to its recipients
+    ///
+    /// # Arguments:
+    /// * `extraction` - results of extraction
+    async fn handle_extraction(&mut self, extraction: Extraction) -> Result<()> {
+        let seq = extraction.seq;
+
+        match extraction.target {
+            ExtractionTarget::Client { addr, frame } => {
+                let sent = self
+                    .send_extracted_data(addr, &frame, seq, extraction.results)
+                    .await;
+
+                let next = match self.client_map.get_mut(&addr) {
+                    Some(client) => client.end_request(),
+                    None => None,
+                };
+
+                if let Some(next) = next {
+                    self.fetch_data_from_channels(addr, &next).await?;
+                }
+
+                sent
+            }
+            ExtractionTarget::Subscribers => {
+                for (name, result) in extraction.results {
+                    self.updating_channels.remove(&name);
+                    let pushed = self.push_channel_update(&name, seq, result).await;
+
+                    // channel changed again while its data was being extracted
+                    if self.outdated_channels.remove(&name) {
+                        self.request_channel_update(&name);
+                    }
+
+                    pushed?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends extracted data to client which requested it
+    ///
+    /// Sends only incremental diff of observed state. Channels which failed to extract data are
+    /// reported with separate err frames, client keeps their last delivered data.
+    ///
+    /// # Arguments:
+    /// * `addr` - socket
+    /// * `frame` - frame received from client
+    /// * `seq` - sequence number of extraction
+    /// * `results` - extraction results of channels
+    async fn send_extracted_data(
+        &mut self,
+        addr: ClientAddr,
+        frame: &Frame,
+        seq: u64,
+        results: Vec<(String, ExtractionResult)>,
+    ) -> Result<()> {
+        let client = match self.client_map.get_mut(&addr) {
+            Some(client) => client,
+            // client disconnected while waiting for data
+            None => return Ok(()),
+        };
+
+        let mut results: HashMap<String, ExtractionResult> = results.into_iter().collect();
+        let mut payload = Map::new();
+        let mut errors = Vec::new();
+
+        // subscriptions might have changed during extraction
+        for k in client.channel_names() {
+            let k = k.as_str();
+
+            match results.remove(k) {
+                // data pushed by later extraction is kept
+                Some(Ok(data)) if client.accept_extraction(k, seq) => {
+                    payload.insert(k.to_string(), data);
+                    continue;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(reason)) => {
+                    log::error!("Channel {}: {}", k, reason);
+                    errors.push(Frame::create_channel_err_frame(
+                        frame.cseq(),
+                        k,
+                        500,
+                        reason,
+                    ));
+                }
+                None => {}
+            }
 
-        let payload = {
-            let mut payload = BTreeMap::new();
-            for chan in client.channels().iter() {
-                let k = chan.name();
-                let data = chan.extract_data(&self.state).await.unwrap();
-                payload.insert(k, data);
+            if let Some(last_data) = client.last_message().and_then(|last| last.get(k)) {
+                payload.insert(k.to_string(), last_data.clone());
             }
+        }
 
-            payload
+        let (delta, reset) = client.create_delta(Value::Object(payload));
+
+        let response = Frame::create_data_frame(
+            frame,
+            client.encoding(),
+            client.version(),
+            reset,
+            delta,
+            client.compression_threshold(),
+        );
+
+        client.send_msg(response).await?;
+
+        for error in errors {
+            client.send_msg(error).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Passes message published by client to the channel
+    ///
+    /// Accepted messages are delivered to all subscribers of the channel.
+    ///
+    /// # Arguments:
+    /// * `addr` - socket
+    /// * `frame` - publish frame received from client
+    /// * `name` - name of channel
+    /// * `payload` - published message
+    async fn publish(
+        &mut self,
+        addr: ClientAddr,
+        frame: &Frame,
+        name: &str,
+        payload: Value,
+    ) -> Result<()> {
+        let channel = match self.channel_map.get(name) {
+            Some(channel) => Arc::clone(channel),
+            None => {
+                let resp = Frame::create_channel_err_frame(
+                    frame.cseq(),
+                    name,
+                    404,
+                    "Channel was not found",
+                );
+                return self.send_to_client(addr, resp).await;
+            }
         };
 
-        let payload = serde_json::to_value(&payload).unwrap();
+        let client = Self::get_client(&mut self.client_map, addr)?;
+        let author = client.member_name();
+        let display_name = client.display_name().map(str::to_string);
+        let identity = client.identity().cloned();
 
-        let mut snapshot = client.take_last_message().unwrap();
-        create_json_snapshot(&mut snapshot, &payload);
+        if !self
+            .policy
+            .allows(identity.as_ref(), Permission::Publish, name)
+        {
+            log::info!("{} is not allowed to publish to channel {}", addr, name);
 
-        let response = Frame::create_data_frame(&frame, snapshot);
-        client.set_last_message(payload);
+            let resp = Frame::create_channel_err_frame(
+                frame.cseq(),
+                name,
+                403,
+                "Not allowed to publish to the channel",
+            );
+            return self.send_to_client(addr, resp).await;
+        }
 
-        client.send_msg(response).await
+        let resp = match channel
+            .publish(
+                &self.state,
+                &author,
+                display_name.as_deref(),
+                identity.as_ref(),
+                payload,
+            )
+            .await
+        {
+            Ok(Publish::Accept(message)) => {
+                log::debug!("{} published message to channel {}", addr, name);
+
+                let subscribers = self
+                    .client_map
+                    .values_mut()
+                    .filter(|client| client.channels().contains(&channel));
+
+                for client in subscribers {
+                    let message_frame = Frame::create_message_frame(name, message.clone());
+
+                    if let Err(e) = client.send_msg(message_frame).await {
+                        log::error!("An error occurred while sending message: {}", e);
+                    }
+                }
+
+                Frame::create_ok_frame(frame)
+            }
+            Ok(Publish::Reject(reason)) => {
+                log::info!("Channel {} rejected message of {}: {}", name, addr, reason);
+                Frame::create_channel_err_frame(frame.cseq(), name, 403, reason)
+            }
+            Err(e) => {
+                log::error!("Failed to publish message to channel {}: {}", name, e);
+                Frame::create_channel_err_frame(
+                    frame.cseq(),
+                    name,
+                    500,
+                    format!("Failed to publish message: {}", e),
+                )
+            }
+        };
+
+        self.send_to_client(addr, resp).await
+    }
+
+    /// Sends full snapshot to client, which lost track of data frames
+    ///
+    /// # Arguments:
+    /// * `addr` - socket
+    /// * `frame` - resync frame received from client
+    async fn resync(&mut self, addr: ClientAddr, frame: &Frame) -> Result<()> {
+        log::info!("{} requested resync", addr);
+        Self::get_client(&mut self.client_map, addr)?.reset_last_message();
+
+        self.fetch_data_from_channels(addr, frame).await
+    }
+
+    /// Starts extraction of changed channel, its data is pushed to subscribers afterwards
+    ///
+    /// Only one extraction of a channel runs at a time, changes made in the meantime are picked
+    /// up by a single extraction started once it finishes.
+    ///
+    /// # Arguments:
+    /// * `name` - name of changed channel
+    fn request_channel_update(&mut self, name: &str) {
+        // cached data is outdated
+        self.cache.invalidate(name);
+
+        if self.updating_channels.contains(name) {
+            self.outdated_channels.insert(name.to_string());
+            return;
+        }
+
+        if let Some(channel) = self.channel_map.get(name) {
+            let channel = Arc::clone(channel);
+
+            self.updating_channels.insert(name.to_string());
+            self.spawn_extraction(vec![channel], ExtractionTarget::Subscribers);
+        }
+    }
+
+    /// Pushes changed channel data to every subscriber.
+    ///
+    /// Channel data is extracted once and merged into the last message delivered to each
+    /// subscriber, so pushed frames carry only incremental diff.
+    ///
+    /// # Arguments:
+    /// * `name` - name of changed channel
+    /// * `seq` - sequence number of extraction
+    /// * `result` - extracted data of the channel
+    async fn push_channel_update(
+        &mut self,
+        name: &str,
+        seq: u64,
+        result: ExtractionResult,
+    ) -> Result<()> {
+        let channel = match self.channel_map.get(name) {
+            Some(channel) => Arc::clone(channel),
+            None => return Ok(()),
+        };
+
+        let subscribers = self
+            .client_map
+            .values_mut()
+            .filter(|client| client.channels().contains(&channel));
+
+        let data = match result {
+            Ok(data) => data,
+            Err(reason) => {
+                for client in subscribers {
+                    let error = Frame::create_channel_err_frame(0, name, 500, reason.as_str());
+
+                    if let Err(e) = client.send_msg(error).await {
+                        log::error!("An error occurred while sending message: {}", e);
+                    }
+                }
+
+                return Err(anyhow!(reason));
+            }
+        };
+
+        for client in subscribers {
+            // client already received data of later extraction
+            if !client.accept_extraction(name, seq) {
+                continue;
+            }
+
+            let mut payload = client.last_message().cloned().unwrap_or_else(|| json!({}));
+            payload[name] = data.clone();
+
+            // nothing has changed from client's point of view
+            if client.last_message() == Some(&payload) {
+                continue;
+            }
+
+            log::debug!("Pushing update of channel {} to {}", name, client.addr());
+
+            let (delta, reset) = client.create_delta(payload);
+            let response = Frame::create_push_frame(
+                client.encoding(),
+                client.version(),
+                reset,
+                delta,
+                client.compression_threshold(),
+            );
+
+            if let Err(e) = client.send_msg(response).await {
+                log::error!("An error occurred while sending message: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keeps session of disconnected client for a grace period
+    ///
+    /// # Arguments:
+    /// * `client` - disconnected client
+    fn park_session(&mut self, client: Client) {
+        if self.session_grace_period == Duration::from_secs(0) {
+            return;
+        }
+
+        let (token, session) = client.into_session();
+        let expires_at = Instant::now() + self.session_grace_period;
+
+        self.session_map.insert(token, (expires_at, session));
+    }
+
+    /// Drops sessions which were not resumed within grace period
+    fn drop_expired_sessions(&mut self) {
+        let now = Instant::now();
+        self.session_map
+            .retain(|_, (expires_at, _)| *expires_at > now);
+    }
+
+    /// Moves parked session to the reconnected client
+    ///
+    /// Client receives a delta since given version. If the version doesn't match the last
+    /// message delivered in the session, a full snapshot is sent instead.
+    ///
+    /// # Arguments:
+    /// * `addr` - socket
+    /// * `frame` - resume frame received from client
+    /// * `token` - session token
+    /// * `version` - version of last data frame applied by client
+    async fn resume_session(
+        &mut self,
+        addr: ClientAddr,
+        frame: &Frame,
+        token: &str,
+        version: u64,
+    ) -> Result<()> {
+        let session = match self.session_map.remove(token) {
+            Some((expires_at, session)) if expires_at > Instant::now() => session,
+            _ => {
+                let resp = Frame::create_err_frame(frame, 404, "Session not found or expired");
+                return self.send_to_client(addr, resp).await;
+            }
+        };
+
+        let in_sync = session.version() == version;
+        let client = Self::get_client(&mut self.client_map, addr)?;
+        let old_member = client.member_name();
+        let old_name = client.display_name().map(str::to_string);
+        let left = client.channel_names();
+
+        client.resume(token.to_string(), session);
+
+        // resumed session might have been created by a client with different permissions
+        let policy = &self.policy;
+        let identity = client.identity().cloned();
+        client
+            .retain_channels(|name| policy.allows(identity.as_ref(), Permission::Subscribe, name));
+
+        let member = client.member_name();
+        let name = client.display_name().map(str::to_string);
+        let joined = client.channel_names();
+
+        if in_sync {
+            log::info!("{} resumed session at version {}", addr, version);
+        } else {
+            log::info!(
+                "{} resumed session at stale version {} - sending full snapshot",
+                addr,
+                version
+            );
+            client.reset_last_message();
+        }
+
+        self.broadcast_presence(&left, &old_member, old_name.as_deref(), false)
+            .await;
+        self.broadcast_presence(&joined, &member, name.as_deref(), true)
+            .await;
+
+        self.fetch_data_from_channels(addr, frame).await
+    }
+
+    /// Sends frame to client
+    ///
+    /// # Arguments:
+    /// * `addr` - socket
+    /// * `frame` - frame to be sent
+    async fn send_to_client(&mut self, addr: ClientAddr, frame: Frame) -> Result<()> {
+        Self::get_client(&mut self.client_map, addr)?
+            .send_msg(frame)
+            .await
     }
 
     /// Finds Client by socket
     ///
+    /// Fails if client is already gone, e.g. event arrived after disconnect
+    ///
     /// # Arguments:
     /// * `client_map` - client map from broker
     /// * `addr` - socket
-    fn get_client(client_map: &mut ClientMap, addr: SocketAddr) -> &mut Client {
-        client_map.get_mut(&addr).unwrap()
+    fn get_client(client_map: &mut ClientMap, addr: ClientAddr) -> Result<&mut Client> {
+        client_map
+            .get_mut(&addr)
+            .ok_or_else(|| anyhow!("Unknown client: {}", addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::channel::{Reward, RoomChannel, StateChannel};
+    use crate::outbox::OutboxConfig;
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+    use futures::SinkExt;
+    use tokio::sync::mpsc::channel;
+    use tungstenite::{Error as WsError, Message};
+
+    fn broker() -> Broker {
+        let (_, rx) = channel(1);
+        Broker::new(rx, State::new(Arc::new(MemoryBackend::default())))
+    }
+
+    async fn connect(broker: &mut Broker, port: u16) -> ClientAddr {
+        let addr = ClientAddr::Tcp(([127, 0, 0, 1], port).into());
+        let tx = futures::sink::drain().sink_map_err(|_| WsError::ConnectionClosed);
+        let client = Client::new(Box::pin(tx), addr, None, OutboxConfig::default());
+
+        broker.handle_event(Event::new_client(addr, client)).await;
+        addr
+    }
+
+    async fn connect_observed(
+        broker: &mut Broker,
+        port: u16,
+    ) -> (ClientAddr, UnboundedReceiver<Message>) {
+        let addr = ClientAddr::Tcp(([127, 0, 0, 1], port).into());
+        let (tx, rx) = unbounded();
+        let tx = tx.sink_map_err(|_| WsError::ConnectionClosed);
+        let client = Client::new(Box::pin(tx), addr, None, OutboxConfig::default());
+
+        broker.handle_event(Event::new_client(addr, client)).await;
+        (addr, rx)
+    }
+
+    /// Returns frames written to websocket so far
+    async fn received(rx: &mut UnboundedReceiver<Message>) -> Vec<Value> {
+        let mut frames = Vec::new();
+        let timeout = Duration::from_millis(50);
+
+        while let Ok(Some(Message::Text(text))) = tokio::time::timeout(timeout, rx.next()).await {
+            frames.push(serde_json::from_str(&text).unwrap());
+        }
+
+        frames
+    }
+
+    fn state_channels(names: &[&str]) -> Vec<Arc<dyn Channel>> {
+        names
+            .iter()
+            .map(|name| Arc::new(StateChannel::new(*name)) as Arc<dyn Channel>)
+            .collect()
+    }
+
+    async fn send(broker: &mut Broker, addr: ClientAddr, frame: &str) {
+        let frame = frame.parse().unwrap();
+        broker
+            .handle_event(Event::new_client_frame(addr, frame))
+            .await;
+    }
+
+    async fn next_extraction(broker: &mut Broker) -> Extraction {
+        broker.extracted_rx.next().await.unwrap()
+    }
+
+    fn last_message(broker: &Broker, addr: ClientAddr) -> Value {
+        broker.client_map[&addr].last_message().cloned().unwrap()
+    }
+
+    #[tokio::test]
+    async fn stale_extraction() {
+        let mut broker = broker();
+        broker.state.backend.put("prices", &json!(1)).await.unwrap();
+        broker.add_channel(Arc::new(StateChannel::new("prices")));
+
+        let addr = connect(&mut broker, 1000).await;
+        send(
+            &mut broker,
+            addr,
+            r#"{"cseq":1,"type":"subscribe","channels":["prices"]}"#,
+        )
+        .await;
+        send(&mut broker, addr, r#"{"cseq":2,"type":"ready"}"#).await;
+        let requested = next_extraction(&mut broker).await;
+
+        broker.state.backend.put("prices", &json!(2)).await.unwrap();
+        broker.request_channel_update("prices");
+        let pushed = next_extraction(&mut broker).await;
+        assert!(requested.seq < pushed.seq);
+
+        // response to ready finishes after the push
+        broker.handle_extraction(pushed).await.unwrap();
+        broker.handle_extraction(requested).await.unwrap();
+        assert_eq!(last_message(&broker, addr), json!({"prices": 2}));
+    }
+
+    #[tokio::test]
+    async fn data_requests() {
+        let mut broker = broker();
+        broker.add_channel(Arc::new(StateChannel::new("prices")));
+
+        let addr = connect(&mut broker, 1000).await;
+        send(
+            &mut broker,
+            addr,
+            r#"{"cseq":1,"type":"subscribe","channels":["prices"]}"#,
+        )
+        .await;
+        send(&mut broker, addr, r#"{"cseq":2,"type":"ready"}"#).await;
+        send(&mut broker, addr, r#"{"cseq":3,"type":"resync"}"#).await;
+        assert_eq!(broker.client_map[&addr].pending_requests(), 1);
+        assert_eq!(broker.extraction_seq, 1);
+
+        let extraction = next_extraction(&mut broker).await;
+        broker.handle_extraction(extraction).await.unwrap();
+        assert_eq!(broker.client_map[&addr].pending_requests(), 0);
+        assert_eq!(broker.extraction_seq, 2);
+
+        let extraction = next_extraction(&mut broker).await;
+        broker.handle_extraction(extraction).await.unwrap();
+        assert_eq!(broker.client_map[&addr].version(), 2);
+        assert_eq!(broker.extraction_seq, 2);
+    }
+
+    #[tokio::test]
+    async fn channel_updates() {
+        let mut broker = broker();
+        broker.add_channel(Arc::new(StateChannel::new("prices")));
+
+        for _ in 0..3 {
+            broker.request_channel_update("prices");
+        }
+        assert_eq!(broker.extraction_seq, 1);
+
+        // changes made during extraction are picked up by a single follow-up extraction
+        let extraction = next_extraction(&mut broker).await;
+        broker.handle_extraction(extraction).await.unwrap();
+        assert_eq!(broker.extraction_seq, 2);
+
+        let extraction = next_extraction(&mut broker).await;
+        broker.handle_extraction(extraction).await.unwrap();
+        assert_eq!(broker.extraction_seq, 2);
+        assert!(broker.updating_channels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reload_channels() {
+        let mut broker = broker();
+        broker.state.backend.put("prices", &json!(1)).await.unwrap();
+        broker.state.backend.put("news", &json!(2)).await.unwrap();
+        broker
+            .reload_channels(state_channels(&["prices", "news"]))
+            .await;
+
+        let (addr, mut rx) = connect_observed(&mut broker, 1000).await;
+        send(
+            &mut broker,
+            addr,
+            r#"{"cseq":1,"type":"subscribe","channels":["prices","ne*"]}"#,
+        )
+        .await;
+        send(&mut broker, addr, r#"{"cseq":2,"type":"ready"}"#).await;
+        let extraction = next_extraction(&mut broker).await;
+        broker.handle_extraction(extraction).await.unwrap();
+        assert_eq!(last_message(&broker, addr), json!({"prices": 1, "news": 2}));
+        received(&mut rx).await;
+
+        broker.reload_channels(state_channels(&["prices"])).await;
+        assert!(!broker.channel_map.contains_key("news"));
+        assert_eq!(last_message(&broker, addr), json!({"prices": 1}));
+
+        let frames = received(&mut rx).await;
+        assert_eq!(frames[0]["type"], "err");
+        assert_eq!(frames[0]["code"], 410);
+        assert_eq!(frames[0]["channel"], "news");
+        assert_eq!(frames[1]["type"], "data");
+        assert_eq!(frames.len(), 2);
+
+        // pattern subscription outlives the channel
+        broker
+            .reload_channels(state_channels(&["prices", "news"]))
+            .await;
+        let mut channels = broker.client_map[&addr].channel_names();
+        channels.sort();
+        assert_eq!(channels, vec!["news", "prices"]);
+    }
+
+    #[tokio::test]
+    async fn reload_channels_kind() {
+        let mut broker = broker();
+        broker.add_channel(Arc::new(Reward {}));
+        broker.reload_channels(state_channels(&["prices"])).await;
+
+        let (addr, mut rx) = connect_observed(&mut broker, 1000).await;
+        send(
+            &mut broker,
+            addr,
+            r#"{"cseq":1,"type":"subscribe","channels":["prices"]}"#,
+        )
+        .await;
+        received(&mut rx).await;
+
+        let channels: Vec<Arc<dyn Channel>> = vec![
+            Arc::new(RoomChannel::new("prices", 10)),
+            Arc::new(RoomChannel::new("reward", 10)),
+        ];
+        broker.reload_channels(channels).await;
+
+        // channel moved to rooms is replaced, subscribers are told about it
+        let room_kind = RoomChannel::new("prices", 10).kind();
+        assert_eq!(broker.channel_map["prices"].kind(), room_kind);
+        let frames = received(&mut rx).await;
+        assert_eq!(frames[0]["code"], 410);
+
+        // channels registered in code are left alone
+        assert_eq!(broker.channel_map["reward"].kind(), Reward {}.kind());
+        assert!(!broker.configured_channels.contains("reward"));
     }
 }
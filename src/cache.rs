@@ -0,0 +1,234 @@
+use futures::future::{BoxFuture, FutureExt, Shared};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Result of data extraction, error contains reason
+pub type ExtractionResult = std::result::Result<Value, String>;
+
+type SharedExtraction = Shared<BoxFuture<'static, ExtractionResult>>;
+
+#[derive(Debug)]
+enum Entry {
+    /// Extraction in progress, awaited by every requester
+    Pending {
+        generation: u64,
+        extraction: SharedExtraction,
+    },
+
+    /// Extracted data valid until given instant
+    Ready { data: Value, expires_at: Instant },
+}
+
+/// Hit and miss counters of the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Channel data shared by all clients
+///
+/// Concurrent requests for the same channel wait for a single extraction, its result is reused
+/// for `ttl` or until channel is invalidated. Failed extractions are not cached.
+#[derive(Debug)]
+pub struct ExtractionCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ExtractionCache {
+    /// Creates empty cache
+    ///
+    /// # Arguments:
+    /// * `ttl` - how long extracted data is reused, zero shares only extractions in progress
+    pub fn new(ttl: Duration) -> ExtractionCache {
+        ExtractionCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns cached data of the channel or extracts it
+    ///
+    /// # Arguments:
+    /// * `name` - name of channel
+    /// * `extract` - extraction of channel data, used on cache miss
+    pub async fn get_or_extract<F>(&self, name: &str, extract: F) -> ExtractionResult
+    where
+        F: Future<Output = ExtractionResult> + Send + 'static,
+    {
+        let (generation, extraction) = {
+            let mut entries = self.entries.lock().unwrap();
+
+            match entries.get(name) {
+                Some(Entry::Ready { data, expires_at }) if *expires_at > Instant::now() => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(data.clone());
+                }
+                Some(Entry::Pending {
+                    generation,
+                    extraction,
+                }) => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    (*generation, extraction.clone())
+                }
+                _ => {
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+
+                    let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+                    let extraction = extract.boxed().shared();
+
+                    entries.insert(
+                        name.to_string(),
+                        Entry::Pending {
+                            generation,
+                            extraction: extraction.clone(),
+                        },
+                    );
+
+                    (generation, extraction)
+                }
+            }
+        };
+
+        let result = extraction.await;
+
+        // first requester to get the result stores it, unless channel was invalidated meanwhile
+        let mut entries = self.entries.lock().unwrap();
+
+        let current = match entries.get(name) {
+            Some(Entry::Pending { generation: g, .. }) => *g == generation,
+            _ => false,
+        };
+
+        if current {
+            match &result {
+                Ok(data) if self.ttl > Duration::from_secs(0) => {
+                    let entry = Entry::Ready {
+                        data: data.clone(),
+                        expires_at: Instant::now() + self.ttl,
+                    };
+                    entries.insert(name.to_string(), entry);
+                }
+                _ => {
+                    entries.remove(name);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Forgets cached data of the channel, next request extracts it again
+    ///
+    /// # Arguments:
+    /// * `name` - name of channel
+    pub fn invalidate(&self, name: &str) {
+        self.entries.lock().unwrap().remove(name);
+    }
+
+    /// Returns hit and miss counters
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn counted(
+        counter: &Arc<AtomicU64>,
+        result: ExtractionResult,
+    ) -> BoxFuture<'static, ExtractionResult> {
+        let counter = Arc::clone(counter);
+
+        async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+            result
+        }
+        .boxed()
+    }
+
+    #[tokio::test]
+    async fn shares_extraction() {
+        let cache = ExtractionCache::new(Duration::from_secs(60));
+        let counter = Arc::new(AtomicU64::new(0));
+
+        let (a, b) = futures::join!(
+            cache.get_or_extract("13", counted(&counter, Ok(json!(1)))),
+            cache.get_or_extract("13", counted(&counter, Ok(json!(2)))),
+        );
+        assert_eq!((a, b), (Ok(json!(1)), Ok(json!(1))));
+
+        let c = cache
+            .get_or_extract("13", counted(&counter, Ok(json!(3))))
+            .await;
+        assert_eq!(c, Ok(json!(1)));
+
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 1 });
+    }
+
+    #[tokio::test]
+    async fn invalidate() {
+        let cache = ExtractionCache::new(Duration::from_secs(60));
+        let counter = Arc::new(AtomicU64::new(0));
+
+        cache
+            .get_or_extract("13", counted(&counter, Ok(json!(1))))
+            .await
+            .unwrap();
+        cache.invalidate("13");
+
+        let data = cache
+            .get_or_extract("13", counted(&counter, Ok(json!(2))))
+            .await;
+        assert_eq!(data, Ok(json!(2)));
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 2 });
+    }
+
+    #[tokio::test]
+    async fn skips_errors_and_zero_ttl() {
+        let cache = ExtractionCache::new(Duration::from_secs(60));
+        let counter = Arc::new(AtomicU64::new(0));
+
+        let failed = cache
+            .get_or_extract("13", counted(&counter, Err("db is down".into())))
+            .await;
+        assert!(failed.is_err());
+
+        let data = cache
+            .get_or_extract("13", counted(&counter, Ok(json!(1))))
+            .await;
+        assert_eq!(data, Ok(json!(1)));
+
+        let cache = ExtractionCache::new(Duration::from_secs(0));
+        cache
+            .get_or_extract("13", counted(&counter, Ok(json!(1))))
+            .await
+            .unwrap();
+        cache
+            .get_or_extract("13", counted(&counter, Ok(json!(2))))
+            .await
+            .unwrap();
+
+        assert_eq!(counter.load(Ordering::SeqCst), 4);
+    }
+}
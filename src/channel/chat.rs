@@ -0,0 +1,54 @@
+use super::{Channel, Publish};
+use crate::{auth::Identity, state::State};
+use anyhow::Result;
+use serde_json::{json, Value};
+
+/// Chat channel - passes published messages to subscribers, keeps no state
+#[derive(Debug)]
+pub struct ChatChannel {
+    name: String,
+}
+
+impl ChatChannel {
+    /// Creates chat channel
+    ///
+    /// # Arguments:
+    /// * `name` - name of the channel
+    pub fn new<S: Into<String>>(name: S) -> ChatChannel {
+        ChatChannel { name: name.into() }
+    }
+}
+
+#[async_trait::async_trait]
+impl Channel for ChatChannel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn extract_data(&self, _state: &State) -> Result<Value> {
+        Ok(json!({}))
+    }
+
+    async fn publish(
+        &self,
+        _state: &State,
+        author: &str,
+        display_name: Option<&str>,
+        _identity: Option<&Identity>,
+        payload: Value,
+    ) -> Result<Publish> {
+        if payload.is_null() {
+            return Ok(Publish::Reject("Empty message".into()));
+        }
+
+        let mut message = json!({
+            "author": author,
+            "body": payload,
+        });
+        if let Some(name) = display_name {
+            message["name"] = json!(name);
+        }
+
+        Ok(Publish::Accept(message))
+    }
+}
@@ -1,18 +1,90 @@
-use crate::state::State;
+use crate::{auth::Identity, frame::HistoryQuery, state::State};
 use anyhow::Result;
 use serde_json::Value;
 use std::{fmt::Debug, hash::Hash};
+use tokio::sync::broadcast;
 
+mod chat;
+mod pattern;
 mod reward;
-mod thirteen_chan;
+mod room;
+mod state;
 
+pub use chat::ChatChannel;
+pub use pattern::ChannelPattern;
 pub use reward::Reward;
-pub use thirteen_chan::ThirteenChan;
+pub use room::RoomChannel;
+pub use state::StateChannel;
+
+/// Channel's decision about message published by client
+#[derive(Debug, PartialEq)]
+pub enum Publish {
+    /// Message accepted, contains message to be sent to all subscribers
+    Accept(Value),
+
+    /// Message rejected, contains reason
+    Reject(String),
+}
 
 #[async_trait::async_trait]
 pub trait Channel: Send + Sync + Debug {
     fn name(&self) -> &str;
+
+    /// Returns kind of channel, channels of the same name and kind are interchangeable
+    fn kind(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
     async fn extract_data(&self, state: &State) -> Result<Value>;
+
+    /// Returns change notification receiver
+    ///
+    /// Every message received means that channel's data changed and subscribers should be updated.
+    /// Channels that never change on their own return `None`
+    ///
+    /// # Arguments:
+    /// * `state` - application state
+    fn updates(&self, _state: &State) -> Option<broadcast::Receiver<()>> {
+        None
+    }
+
+    /// Accepts or rejects message published by client
+    ///
+    /// Channels are read-only by default
+    ///
+    /// # Arguments:
+    /// * `state` - application state
+    /// * `author` - publisher of the message, authenticated subject if there is any
+    /// * `display_name` - display name of publisher
+    /// * `identity` - authenticated identity of publisher
+    /// * `payload` - message published by client
+    async fn publish(
+        &self,
+        _state: &State,
+        _author: &str,
+        _display_name: Option<&str>,
+        _identity: Option<&Identity>,
+        _payload: Value,
+    ) -> Result<Publish> {
+        Ok(Publish::Reject("Channel is read-only".into()))
+    }
+
+    /// Returns past messages, replayed to client on subscribe
+    ///
+    /// Channels keep no history by default
+    ///
+    /// # Arguments:
+    /// * `state` - application state
+    /// * `identity` - authenticated identity of subscriber
+    /// * `query` - range of requested messages
+    async fn history(
+        &self,
+        _state: &State,
+        _identity: Option<&Identity>,
+        _query: HistoryQuery,
+    ) -> Result<Vec<Value>> {
+        Ok(Vec::new())
+    }
 }
 
 impl Hash for dyn Channel {
@@ -0,0 +1,180 @@
+use std::fmt;
+
+/// Channel name pattern
+///
+/// Channel names are split into dot separated segments. Pattern matches names with the same
+/// number of segments, where:
+/// * `{placeholder}` matches any segment, e.g. `user.{id}.alerts`
+/// * `*` matches any sequence of characters within a segment, e.g. `prices.*` or `prices.eu*`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChannelPattern {
+    pattern: String,
+}
+
+impl ChannelPattern {
+    /// Creates pattern
+    ///
+    /// # Arguments:
+    /// * `pattern` - pattern string
+    pub fn new<S: Into<String>>(pattern: S) -> ChannelPattern {
+        ChannelPattern {
+            pattern: pattern.into(),
+        }
+    }
+
+    /// Checks if subscription request refers to a pattern rather than exact channel name
+    ///
+    /// # Arguments:
+    /// * `name` - requested channel name
+    pub fn is_pattern(name: &str) -> bool {
+        name.contains('*') || name.split('.').any(is_placeholder)
+    }
+
+    /// Returns pattern string
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Checks if channel name matches the pattern
+    ///
+    /// # Arguments:
+    /// * `name` - channel name
+    pub fn matches(&self, name: &str) -> bool {
+        self.matches_bound(name, &[])
+    }
+
+    /// Checks if channel name matches the pattern with some placeholders bound to values
+    ///
+    /// Bound placeholder matches only the segment equal to its value, or nothing if the value is
+    /// missing. Other placeholders match any segment.
+    ///
+    /// # Arguments:
+    /// * `name` - channel name
+    /// * `bindings` - placeholder names (without braces) and their values
+    pub fn matches_bound(&self, name: &str, bindings: &[(&str, Option<&str>)]) -> bool {
+        let mut pattern_segments = self.pattern.split('.');
+        let mut name_segments = name.split('.');
+
+        loop {
+            match (pattern_segments.next(), name_segments.next()) {
+                (Some(pattern), Some(segment)) => {
+                    let segment_matches = if is_placeholder(pattern) {
+                        let placeholder = &pattern[1..pattern.len() - 1];
+
+                        match bindings.iter().find(|(name, _)| *name == placeholder) {
+                            Some((_, value)) => *value == Some(segment),
+                            None => !segment.is_empty(),
+                        }
+                    } else {
+                        wildcard_match(pattern.as_bytes(), segment.as_bytes())
+                    };
+
+                    if !segment_matches {
+                        return false;
+                    }
+                }
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl fmt::Display for ChannelPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+fn is_placeholder(segment: &str) -> bool {
+    segment.len() > 2 && segment.starts_with('{') && segment.ends_with('}')
+}
+
+/// Matches single segment, `*` matches any sequence of characters
+///
+/// On mismatch only the most recent `*` is extended, which keeps matching linear in the segment
+/// length for each star instead of exponential.
+fn wildcard_match(pattern: &[u8], segment: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // position after the last seen `*` and the segment position it currently extends to
+    let mut backtrack = None;
+
+    while s < segment.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, s));
+            }
+            Some(&c) if c == segment[s] => {
+                p += 1;
+                s += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_s)) => {
+                    p = star_p;
+                    s = star_s + 1;
+                    backtrack = Some((star_p, s));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn is_pattern() {
+        assert!(ChannelPattern::is_pattern("prices.*"));
+        assert!(ChannelPattern::is_pattern("user.{id}.alerts"));
+        assert!(!ChannelPattern::is_pattern("prices.eu"));
+        assert!(!ChannelPattern::is_pattern("{}"));
+    }
+
+    #[test]
+    fn matches() {
+        let pattern = ChannelPattern::new("prices.*");
+        assert!(pattern.matches("prices.eu"));
+        assert!(pattern.matches("prices."));
+        assert!(!pattern.matches("prices"));
+        assert!(!pattern.matches("prices.eu.btc"));
+        assert!(!pattern.matches("news.eu"));
+
+        let pattern = ChannelPattern::new("user.{id}.alerts");
+        assert!(pattern.matches("user.42.alerts"));
+        assert!(!pattern.matches("user..alerts"));
+        assert!(!pattern.matches("user.42.news"));
+
+        let pattern = ChannelPattern::new("prices.e*r*");
+        assert!(pattern.matches("prices.eur"));
+        assert!(pattern.matches("prices.er"));
+        assert!(!pattern.matches("prices.usd"));
+
+        let pattern = ChannelPattern::new("prices.**e**");
+        assert!(pattern.matches("prices.e"));
+        assert!(pattern.matches("prices.usde"));
+        assert!(!pattern.matches("prices.usd"));
+    }
+
+    #[test]
+    fn matches_bound() {
+        let pattern = ChannelPattern::new("user.{sub}.{topic}");
+        let bindings = [("sub", Some("42"))];
+        assert!(pattern.matches_bound("user.42.alerts", &bindings));
+        assert!(!pattern.matches_bound("user.43.alerts", &bindings));
+        assert!(!pattern.matches_bound("user.42.alerts", &[("sub", None)]));
+        assert!(pattern.matches("user.43.alerts"));
+    }
+
+    #[test]
+    fn matches_pathological() {
+        let pattern = ChannelPattern::new("*a*a*a*a*a*a*a*a*a*a*a*a*b");
+        let name = "a".repeat(10_000);
+        assert!(!pattern.matches(&name));
+        assert!(pattern.matches(&format!("{}b", name)));
+    }
+}
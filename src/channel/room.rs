@@ -0,0 +1,96 @@
+use super::{Channel, Publish};
+use crate::auth::Identity;
+use crate::backend::StoredMessage;
+use crate::frame::HistoryQuery;
+use crate::state::State;
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Chat room - messages are stored in state backend and can be replayed on subscribe
+#[derive(Debug)]
+pub struct RoomChannel {
+    name: String,
+    max_history: u32,
+}
+
+impl RoomChannel {
+    /// Creates chat room
+    ///
+    /// # Arguments:
+    /// * `name` - name of the room, also a name of the channel
+    /// * `max_history` - maximal number of messages replayed on subscribe
+    pub fn new<S: Into<String>>(name: S, max_history: u32) -> RoomChannel {
+        RoomChannel {
+            name: name.into(),
+            max_history,
+        }
+    }
+
+    /// Converts stored message to the form delivered to subscribers
+    fn message(message: StoredMessage) -> Value {
+        json!({
+            "id": message.id,
+            "author": message.author,
+            "timestamp": message.created_at,
+            "body": message.body,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Channel for RoomChannel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn extract_data(&self, _state: &State) -> Result<Value> {
+        Ok(json!({}))
+    }
+
+    async fn publish(
+        &self,
+        state: &State,
+        author: &str,
+        display_name: Option<&str>,
+        _identity: Option<&Identity>,
+        payload: Value,
+    ) -> Result<Publish> {
+        if payload.is_null() {
+            return Ok(Publish::Reject("Empty message".into()));
+        }
+
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        let id = state
+            .backend
+            .append_message(&self.name, author, created_at, &payload)
+            .await?;
+
+        let mut message = Self::message(StoredMessage {
+            id,
+            author: author.to_string(),
+            created_at,
+            body: payload,
+        });
+        // display names aren't stored, replayed messages carry the author only
+        if let Some(name) = display_name {
+            message["name"] = json!(name);
+        }
+
+        Ok(Publish::Accept(message))
+    }
+
+    async fn history(
+        &self,
+        state: &State,
+        _identity: Option<&Identity>,
+        query: HistoryQuery,
+    ) -> Result<Vec<Value>> {
+        let messages = state
+            .backend
+            .messages(&self.name, query, self.max_history)
+            .await?;
+
+        Ok(messages.into_iter().map(Self::message).collect())
+    }
+}
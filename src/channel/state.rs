@@ -0,0 +1,53 @@
+use super::Channel;
+use crate::state::State;
+use anyhow::Result;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+/// Channel serving payload stored in state backend
+#[derive(Debug)]
+pub struct StateChannel {
+    name: String,
+}
+
+impl StateChannel {
+    /// Creates channel serving payload of given name
+    ///
+    /// # Arguments:
+    /// * `name` - name of the payload in state backend, also a name of the channel
+    pub fn new<S: Into<String>>(name: S) -> StateChannel {
+        StateChannel { name: name.into() }
+    }
+
+    /// Creates channel for every payload stored in state backend
+    ///
+    /// # Arguments:
+    /// * `state` - application state
+    pub async fn discover(state: &State) -> Result<Vec<StateChannel>> {
+        let channels = state
+            .backend
+            .list()
+            .await?
+            .into_iter()
+            .map(StateChannel::new)
+            .collect();
+
+        Ok(channels)
+    }
+}
+
+#[async_trait::async_trait]
+impl Channel for StateChannel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn extract_data(&self, state: &State) -> Result<Value> {
+        let res = state.backend.get(&self.name).await?;
+        Ok(res.unwrap_or_else(|| json!({})))
+    }
+
+    fn updates(&self, state: &State) -> Option<broadcast::Receiver<()>> {
+        Some(state.backend.watch(&self.name))
+    }
+}
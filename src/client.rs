@@ -1,85 +1,578 @@
-use crate::{broker::Event, channel::Channel, frame::Frame};
+use crate::{
+    auth::{bearer_token, Authenticator, Identity},
+    broker::Event,
+    channel::{Channel, ChannelPattern},
+    frame::{DeltaEncoding, Frame, FrameData, DEFAULT_COMPRESSION_THRESHOLD},
+    outbox::{Outbox, OutboxConfig, SlowConsumerPolicy},
+};
 use anyhow::{Context, Result};
-use futures::{stream::SplitSink, SinkExt, StreamExt};
+use futures::{Sink, StreamExt};
 use serde_json::{json, Value};
-use std::collections::HashSet;
-use std::{convert::TryFrom, net::SocketAddr, sync::Arc};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio_tungstenite::WebSocketStream;
-use tungstenite::Message;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::{convert::TryFrom, fmt, net::SocketAddr, pin::Pin, sync::Arc};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::Sender;
+use tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tungstenite::{Error as WsError, Message};
+use uuid::Uuid;
 
-pub type ClientTx = SplitSink<WebSocketStream<TcpStream>, Message>;
+/// Address identifying client's connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClientAddr {
+    /// TCP peer
+    Tcp(SocketAddr),
+
+    /// Unix socket peer, numbered in order of connection
+    Unix(u64),
+}
+
+impl fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientAddr::Tcp(addr) => write!(f, "{}", addr),
+            ClientAddr::Unix(id) => write!(f, "unix:{}", id),
+        }
+    }
+}
+
+/// Websocket write half, independent of the underlying stream (plain TCP, TLS)
+pub type ClientTx = Pin<Box<dyn Sink<Message, Error = WsError> + Send + Sync>>;
 
 /// Contains client session info
-#[derive(Debug)]
 pub struct Client {
-    tx: ClientTx,
-    addr: SocketAddr,
+    outbox: Outbox,
+    slow_consumer: SlowConsumerPolicy,
+    addr: ClientAddr,
+    token: String,
+    identity: Option<Identity>,
+    session: Session,
+    compression_threshold: usize,
+    extracting: bool,
+    pending_requests: VecDeque<Frame>,
+}
+
+/// Client's state which outlives the connection
+///
+/// Kept by broker for a grace period after disconnect, so reconnecting client can resume it
+#[derive(Debug)]
+pub struct Session {
+    /// last delivered message
     last_message: Option<Value>,
+
+    /// subscribed channels
     channels: HashSet<Arc<dyn Channel>>,
+
+    /// names of channels subscribed by name rather than by pattern
+    explicit: HashSet<String>,
+
+    /// subscribed channel patterns
+    patterns: HashSet<ChannelPattern>,
+
+    /// channels whose presence is observed
+    presence: HashSet<String>,
+
+    /// name set by client
+    display_name: Option<String>,
+
+    /// delta encoding of data frames
+    encoding: DeltaEncoding,
+
+    /// version of last delivered message
+    version: u64,
+
+    /// next delta is full snapshot replacing client's data
+    reset: bool,
+
+    /// sequence numbers of extractions which delivered data of channels
+    extractions: HashMap<String, u64>,
+}
+
+impl Session {
+    /// Creates new session without subscriptions
+    pub fn new() -> Session {
+        Session {
+            last_message: Some(json!({})),
+            channels: HashSet::new(),
+            explicit: HashSet::new(),
+            patterns: HashSet::new(),
+            presence: HashSet::new(),
+            display_name: None,
+            encoding: DeltaEncoding::default(),
+            version: 0,
+            reset: false,
+            extractions: HashMap::new(),
+        }
+    }
+
+    /// Returns version of last delivered message
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Subscribes to newly registered channel if it matches any of subscribed patterns
+    ///
+    /// # Arguments:
+    /// * `channel` - channel pointer
+    pub fn subscribe_if_matches(&mut self, channel: &Arc<dyn Channel>) -> bool {
+        let matches = self
+            .patterns
+            .iter()
+            .any(|pattern| pattern.matches(channel.name()));
+
+        if matches {
+            self.channels.insert(Arc::clone(channel));
+        }
+
+        matches
+    }
+
+    /// Unsubscribes from channel pattern and channels subscribed only through it
+    ///
+    /// Channels subscribed by name or matching other subscribed pattern are kept. Returns names of
+    /// unsubscribed channels.
+    ///
+    /// # Arguments:
+    /// * `pattern` - channel pattern
+    pub fn unsubscribe_pattern(&mut self, pattern: &ChannelPattern) -> Vec<String> {
+        self.patterns.remove(pattern);
+
+        let explicit = &self.explicit;
+        let patterns = &self.patterns;
+        let mut unsubscribed = Vec::new();
+
+        self.channels.retain(|channel| {
+            let name = channel.name();
+            let dropped = pattern.matches(name)
+                && !explicit.contains(name)
+                && !patterns.iter().any(|other| other.matches(name));
+
+            if dropped {
+                unsubscribed.push(name.to_string());
+            }
+
+            !dropped
+        });
+
+        unsubscribed
+    }
+
+    /// Drops subscriptions and observed presence of channels which are not allowed
+    ///
+    /// # Arguments:
+    /// * `allowed` - returns `true` for channels which are kept
+    pub fn retain_channels<F: Fn(&str) -> bool>(&mut self, allowed: F) {
+        self.channels.retain(|channel| allowed(channel.name()));
+        self.explicit.retain(|channel| allowed(channel));
+        self.extractions.retain(|channel, _| allowed(channel));
+        self.presence.retain(|channel| allowed(channel));
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("addr", &self.addr)
+            .field("token", &self.token)
+            .field("identity", &self.identity)
+            .field("session", &self.session)
+            .finish()
+    }
 }
 
 impl Client {
     /// Creates new client
     ///
     /// # Arguments:
-    /// * `tx` - websocket write half
+    /// * `tx` - websocket write half, drained by outbox writer task
     /// * `addr` - socket
-    /// * `last_message` - last delivered message
-    /// * `channels` - subscribed channels
-    pub fn new(tx: ClientTx, addr: SocketAddr) -> Client {
+    /// * `identity` - identity proven during handshake, `None` if authentication is disabled
+    /// * `outbox` - outbound queue settings
+    pub fn new(
+        tx: ClientTx,
+        addr: ClientAddr,
+        identity: Option<Identity>,
+        outbox: OutboxConfig,
+    ) -> Client {
         Client {
-            tx,
+            outbox: Outbox::new(tx, outbox.capacity),
+            slow_consumer: outbox.policy,
             addr,
-            last_message: Some(json!({})),
-            channels: HashSet::new(),
+            token: Uuid::new_v4().to_string(),
+            identity,
+            session: Session::new(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            extracting: false,
+            pending_requests: VecDeque::new(),
         }
     }
 
-    /// Subscribes to channel
+    /// Subscribes to channel by name, returns `false` if already subscribed
+    ///
+    /// # Arguments:
+    /// * `channel` - channel pointer
+    pub fn subscribe(&mut self, channel: Arc<dyn Channel>) -> bool {
+        self.session.explicit.insert(channel.name().to_string());
+        self.session.channels.insert(channel)
+    }
+
+    /// Subscribes to channel matching requested pattern, returns `false` if already subscribed
+    ///
+    /// # Arguments:
+    /// * `channel` - channel pointer
+    pub fn subscribe_matching(&mut self, channel: Arc<dyn Channel>) -> bool {
+        self.session.channels.insert(channel)
+    }
+
+    /// Unsubscribes from channel, returns `false` if wasn't subscribed
     ///
     /// # Arguments:
     /// * `channel` - channel pointer
-    pub fn subscribe(&mut self, channel: Arc<dyn Channel>) {
-        self.channels.insert(channel);
+    pub fn unsubscribe(&mut self, channel: Arc<dyn Channel>) -> bool {
+        self.session.explicit.remove(channel.name());
+        self.session.channels.remove(&channel)
+    }
+
+    /// Subscribes to channel pattern
+    ///
+    /// Channels registered later are subscribed as long as they match the pattern
+    ///
+    /// # Arguments:
+    /// * `pattern` - channel pattern
+    pub fn subscribe_pattern(&mut self, pattern: ChannelPattern) {
+        self.session.patterns.insert(pattern);
+    }
+
+    /// Unsubscribes from channel pattern and channels subscribed only through it
+    ///
+    /// Returns names of unsubscribed channels
+    ///
+    /// # Arguments:
+    /// * `pattern` - channel pattern
+    pub fn unsubscribe_pattern(&mut self, pattern: &ChannelPattern) -> Vec<String> {
+        self.session.unsubscribe_pattern(pattern)
+    }
+
+    /// Drops subscriptions to channels, whose name doesn't satisfy the predicate
+    ///
+    /// # Arguments:
+    /// * `allowed` - predicate deciding which channels are kept
+    pub fn retain_channels<F: Fn(&str) -> bool>(&mut self, allowed: F) {
+        self.session.retain_channels(allowed)
+    }
+
+    /// Starts observing presence of the channel
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    pub fn watch_presence(&mut self, channel: &str) {
+        self.session.presence.insert(channel.to_string());
+    }
+
+    /// Stops observing presence of the channel
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    pub fn unwatch_presence(&mut self, channel: &str) {
+        self.session.presence.remove(channel);
+    }
+
+    /// Checks if client observes presence of the channel
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    pub fn watches_presence(&self, channel: &str) -> bool {
+        self.session.presence.contains(channel)
+    }
+
+    /// Setter for display name
+    ///
+    /// # Arguments:
+    /// * `name` - name chosen by client
+    pub fn set_display_name(&mut self, name: String) {
+        self.session.display_name = Some(name);
+    }
+
+    /// Returns name identifying client among channel members and as author of messages
+    ///
+    /// Authenticated clients are identified by subject, display name can't impersonate anyone.
+    /// Anonymous clients fall back to display name or socket address.
+    pub fn member_name(&self) -> String {
+        match (&self.identity, &self.session.display_name) {
+            (Some(identity), _) => identity.subject.clone(),
+            (None, Some(name)) => name.clone(),
+            (None, None) => self.addr.to_string(),
+        }
+    }
+
+    /// Returns display name chosen by client
+    pub fn display_name(&self) -> Option<&str> {
+        self.session.display_name.as_deref()
+    }
+
+    /// Returns identity proven during handshake
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
     }
 
-    /// Unsubscribes from channel
+    /// Subscribes to newly registered channel if it matches any of subscribed patterns
     ///
     /// # Arguments:
     /// * `channel` - channel pointer
-    pub fn unsubscribe(&mut self, channel: Arc<dyn Channel>) {
-        self.channels.remove(&channel);
+    pub fn subscribe_if_matches(&mut self, channel: &Arc<dyn Channel>) -> bool {
+        self.session.subscribe_if_matches(channel)
     }
 
     /// Returns socket addr
-    pub fn addr(&self) -> SocketAddr {
+    pub fn addr(&self) -> ClientAddr {
         self.addr
     }
 
+    /// Returns session token
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Detaches session from the connection
+    pub fn into_session(self) -> (String, Session) {
+        (self.token, self.session)
+    }
+
+    /// Takes over session of previous connection
+    ///
+    /// # Arguments:
+    /// * `token` - token of resumed session
+    /// * `session` - resumed session
+    pub fn resume(&mut self, token: String, session: Session) {
+        self.token = token;
+        self.session = session;
+    }
+
     /// Returns subscription list
     pub fn channels(&self) -> &HashSet<Arc<dyn Channel>> {
-        &self.channels
+        &self.session.channels
+    }
+
+    /// Returns names of subscribed channels
+    pub fn channel_names(&self) -> Vec<String> {
+        self.session
+            .channels
+            .iter()
+            .map(|channel| channel.name().to_string())
+            .collect()
     }
 
     /// Yanks last message
     pub fn take_last_message(&mut self) -> Option<Value> {
-        self.last_message.take()
+        self.session.last_message.take()
     }
 
     /// Setter for last message
     pub fn set_last_message(&mut self, last_message: Value) {
-        self.last_message = Some(last_message)
+        self.session.last_message = Some(last_message)
+    }
+
+    /// Forgets last delivered message, next delta will contain full snapshot
+    pub fn reset_last_message(&mut self) {
+        self.session.last_message = Some(json!({}));
+        self.session.reset = true;
+    }
+
+    /// Returns version of last delivered message
+    pub fn version(&self) -> u64 {
+        self.session.version
+    }
+
+    /// Returns last message without yanking it
+    pub fn last_message(&self) -> Option<&Value> {
+        self.session.last_message.as_ref()
+    }
+
+    /// Returns delta encoding of data frames
+    pub fn encoding(&self) -> DeltaEncoding {
+        self.session.encoding
+    }
+
+    /// Setter for delta encoding
+    pub fn set_encoding(&mut self, encoding: DeltaEncoding) {
+        self.session.encoding = encoding;
+    }
+
+    /// Returns length of payload, above which data frames are compressed
+    pub fn compression_threshold(&self) -> usize {
+        self.compression_threshold
+    }
+
+    /// Setter for compression threshold
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compression_threshold = threshold;
     }
 
-    /// Writes frame to websocket
+    /// Records extraction which delivers data of the channel
+    ///
+    /// Returns `false` if client already received data of a later extraction, stale data must not
+    /// overwrite it then.
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    /// * `seq` - sequence number of extraction
+    pub fn accept_extraction(&mut self, channel: &str, seq: u64) -> bool {
+        match self.session.extractions.get_mut(channel) {
+            Some(delivered) if *delivered > seq => false,
+            Some(delivered) => {
+                *delivered = seq;
+                true
+            }
+            None => {
+                self.session.extractions.insert(channel.to_string(), seq);
+                true
+            }
+        }
+    }
+
+    /// Starts serving data request, returns `false` if another one is in progress
+    ///
+    /// Requests are served one at a time, the frame is queued until preceding requests finish.
+    ///
+    /// # Arguments:
+    /// * `frame` - frame requesting data
+    pub fn begin_request(&mut self, frame: &Frame) -> bool {
+        if self.extracting {
+            self.pending_requests.push_back(frame.clone());
+            return false;
+        }
+
+        self.extracting = true;
+        true
+    }
+
+    /// Finishes data request in progress, returns the next queued one
+    pub fn end_request(&mut self) -> Option<Frame> {
+        self.extracting = false;
+        self.pending_requests.pop_front()
+    }
+
+    /// Returns number of data requests waiting for the one in progress
+    pub fn pending_requests(&self) -> usize {
+        self.pending_requests.len()
+    }
+
+    /// Creates incremental diff against last delivered message and stores `payload` as the new one
+    ///
+    /// Diff is encoded with client's delta encoding, message version is bumped. Returns the diff
+    /// and whether it is a full snapshot replacing client's data, e.g. after resync.
+    ///
+    /// # Arguments:
+    /// * `payload` - complete data of observed channels
+    pub fn create_delta(&mut self, payload: Value) -> (Value, bool) {
+        let last_message = self.take_last_message().unwrap_or_else(|| json!({}));
+        let delta = self.session.encoding.encode(&last_message, &payload);
+        self.set_last_message(payload);
+        self.session.version += 1;
+
+        let reset = self.session.reset;
+        self.session.reset = false;
+
+        (delta, reset)
+    }
+
+    /// Queues frame for sending to websocket
+    ///
+    /// If client doesn't keep up with reading and the queue is full, slow consumer policy decides
+    /// what happens with the frame.
     ///
     /// # Arguments:
     /// * `frame` - frame from broker
     pub async fn send_msg(&mut self, frame: Frame) -> Result<()> {
-        let message = frame.socket_msg();
-        self.tx.send(message).await?;
+        let frame = match self.outbox.push(frame)? {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+
+        match self.slow_consumer {
+            SlowConsumerPolicy::DropOldest => {
+                log::warn!("{} is too slow, dropping oldest frame", self.addr);
+                self.outbox.drop_oldest();
+                self.outbox.force_push(frame);
+            }
+            SlowConsumerPolicy::Coalesce => {
+                log::warn!("{} is too slow, coalescing data frames", self.addr);
+                self.coalesce(frame);
+            }
+            SlowConsumerPolicy::Disconnect => {
+                log::warn!("{} is too slow, disconnecting", self.addr);
+                self.outbox
+                    .close(close_frame(CloseCode::Policy, "Client is too slow"), true);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces queued data frames with a snapshot of client's data
+    ///
+    /// Frames other than the snapshot never exceed queue capacity, the oldest of them is dropped
+    /// to make room for a new one. Only the snapshot may take a slot above capacity.
+    ///
+    /// # Arguments:
+    /// * `frame` - frame which didn't fit into the queue
+    fn coalesce(&mut self, frame: Frame) {
+        let is_data = matches!(frame.data(), FrameData::Data { .. });
+        let dropped = self.outbox.drop_data_frames();
+
+        let snapshot_frame = if is_data || dropped > 0 {
+            let last_message = self.last_message().cloned().unwrap_or_else(|| json!({}));
+            let snapshot = self.encoding().encode(&json!({}), &last_message);
+            // data frame responding to client's request keeps its cseq
+            Some(if is_data {
+                Frame::create_data_frame(
+                    &frame,
+                    self.encoding(),
+                    self.version(),
+                    true,
+                    snapshot,
+                    self.compression_threshold,
+                )
+            } else {
+                Frame::create_push_frame(
+                    self.encoding(),
+                    self.version(),
+                    true,
+                    snapshot,
+                    self.compression_threshold,
+                )
+            })
+        } else {
+            None
+        };
+
+        if !is_data {
+            // no data frame is queued now, so the snapshot is never dropped here
+            if self.outbox.is_full() {
+                self.outbox.drop_oldest();
+            }
+            self.outbox.force_push(frame);
+        }
+
+        if let Some(snapshot_frame) = snapshot_frame {
+            self.outbox.force_push(snapshot_frame);
+        }
+    }
+
+    /// Initiates websocket close handshake once already queued frames are sent
+    ///
+    /// # Arguments:
+    /// * `code` - close status code
+    /// * `reason` - human readable reason
+    pub async fn close(&mut self, code: CloseCode, reason: &str) -> Result<()> {
+        self.outbox.close(close_frame(code, reason), false);
 
         Ok(())
     }
@@ -90,26 +583,60 @@ impl Client {
 /// Manages client's connection lifecycle - negotates the session, pushes incoming messages towards broker
 /// and sends disconnect event at the end of the session
 ///
+/// Malformed messages are rejected with err frames. Client is disconnected after sending
+/// `max_invalid_messages` malformed messages in a row.
+///
+/// If authenticator is given, upgrade request has to carry a valid bearer token, otherwise
+/// handshake is rejected with `401 Unauthorized`.
+///
 /// # Arguments:
-/// * `raw_stream` - TCP connection to client
-/// * `broker_tx` - broker's mpsc channel write half
-pub async fn handle_connection(
-    raw_stream: TcpStream,
-    broker_tx: UnboundedSender<Event>,
-) -> Result<()> {
-    let addr = raw_stream.peer_addr()?;
-    log::info!("Incoming TCP connection from: {}", addr);
-
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream)
+/// * `raw_stream` - connection to client, plain TCP, TLS or Unix socket
+/// * `addr` - client's address
+/// * `broker_tx` - broker's mpsc channel write half, reading stalls while broker's queue is full
+/// * `max_invalid_messages` - limit of consecutive malformed messages
+/// * `authenticator` - verifier of bearer tokens, `None` disables authentication
+/// * `outbox` - settings of client's outbound queue
+pub async fn handle_connection<S>(
+    raw_stream: S,
+    addr: ClientAddr,
+    mut broker_tx: Sender<Event>,
+    max_invalid_messages: usize,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    outbox: OutboxConfig,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    log::info!("Incoming connection from: {}", addr);
+
+    let mut identity = None;
+
+    let authenticate = Authenticate {
+        authenticator,
+        addr,
+        identity: &mut identity,
+    };
+
+    let ws_stream = tokio_tungstenite::accept_hdr_async(raw_stream, authenticate)
         .await
         .with_context(|| "Error during the websocket handshake occurred")?;
 
-    log::info!("WebSocket connection established: {}", addr);
+    match &identity {
+        Some(identity) => log::info!(
+            "WebSocket connection established: {} as {}",
+            addr,
+            identity.subject
+        ),
+        None => log::info!("WebSocket connection established: {}", addr),
+    }
 
     let (outgoing, mut incoming) = ws_stream.split();
 
     // push session info towards broker
-    broker_tx.send(Event::new_client(addr, Client::new(outgoing, addr)))?;
+    let client = Client::new(Box::pin(outgoing), addr, identity, outbox);
+    broker_tx.send(Event::new_client(addr, client)).await?;
+
+    let mut invalid_messages = 0;
 
     // read incoming messages
     while let Some(msg) = incoming.next().await {
@@ -123,26 +650,281 @@ pub async fn handle_connection(
 
         log::debug!("Received msg from addr={}", addr);
 
-        // unpack message or wait for next one
+        match &msg {
+            Message::Close(close_frame) => {
+                // tungstenite replies with close frame on its own, stream ends afterwards
+                log::info!("{} closed connection: {:?}", addr, close_frame);
+                continue;
+            }
+            // tungstenite answers pings on its own
+            Message::Ping(_) | Message::Pong(_) => continue,
+            _ => {}
+        }
+
+        // unpack message or reject it and wait for next one
         let frame = match Frame::try_from(&msg) {
-            Ok(frame) => frame,
+            Ok(frame) => {
+                invalid_messages = 0;
+                frame
+            }
             Err(e) => {
                 log::info!("Failed to unpack msg: {:?}, {}", msg, e);
+                broker_tx
+                    .send(Event::reply(addr, Frame::create_rejection_frame(&e)))
+                    .await?;
+
+                invalid_messages += 1;
+                if invalid_messages >= max_invalid_messages {
+                    log::info!("{} sent too many invalid messages, disconnecting", addr);
+                    broker_tx
+                        .send(Event::close(
+                            addr,
+                            CloseCode::Policy,
+                            "Too many invalid messages",
+                        ))
+                        .await?;
+                    break;
+                }
+
                 continue;
             }
         };
 
         log::debug!("Unpacked frame: {:?}", frame);
 
-        broker_tx.send(Event::new_client_frame(addr, frame))?;
+        broker_tx.send(Event::new_client_frame(addr, frame)).await?;
     }
 
-    // TODO: Handle websocket close handshake
-
     // EOF - send disconnect event
-    broker_tx.send(Event::disconnect(addr))?;
+    broker_tx.send(Event::disconnect(addr)).await?;
 
     log::info!("{} disconnected", &addr);
 
     Ok(())
 }
+
+/// Handshake callback verifying bearer token of the client
+struct Authenticate<'a> {
+    authenticator: Option<Arc<dyn Authenticator>>,
+    addr: ClientAddr,
+    identity: &'a mut Option<Identity>,
+}
+
+impl Callback for Authenticate<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        let authenticator = match self.authenticator {
+            Some(authenticator) => authenticator,
+            None => return Ok(response),
+        };
+
+        let verified = bearer_token(request)
+            .ok_or_else(|| anyhow::anyhow!("Missing bearer token"))
+            .and_then(|token| authenticator.authenticate(token));
+
+        match verified {
+            Ok(verified) => {
+                *self.identity = Some(verified);
+                Ok(response)
+            }
+            Err(e) => {
+                log::info!("{} failed to authenticate: {}", self.addr, e);
+                Err(unauthorized(e.to_string()))
+            }
+        }
+    }
+}
+
+/// Creates handshake response rejecting unauthenticated client
+///
+/// # Arguments:
+/// * `reason` - human readable reason
+fn unauthorized(reason: String) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(reason));
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+
+    response
+}
+
+/// Creates close frame
+///
+/// # Arguments:
+/// * `code` - close status code
+/// * `reason` - human readable reason
+fn close_frame(code: CloseCode, reason: &str) -> CloseFrame<'static> {
+    CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::StateChannel;
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+    use futures::SinkExt;
+
+    fn client(policy: SlowConsumerPolicy) -> (Client, UnboundedReceiver<Message>) {
+        let (tx, rx) = unbounded();
+        let tx = tx.sink_map_err(|_| WsError::ConnectionClosed);
+        let addr = ClientAddr::Tcp(([127, 0, 0, 1], 9000).into());
+        let outbox = OutboxConfig {
+            capacity: 2,
+            policy,
+        };
+
+        (Client::new(Box::pin(tx), addr, None, outbox), rx)
+    }
+
+    /// Returns messages written to websocket once client is dropped
+    ///
+    /// Outbox writer doesn't run until the test yields, so frames pile up in the queue before.
+    async fn written(client: Client, rx: UnboundedReceiver<Message>) -> Vec<Value> {
+        drop(client);
+        rx.map(|msg| match msg {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            Message::Close(Some(close_frame)) => {
+                json!({ "close": u16::from(close_frame.code), "reason": close_frame.reason })
+            }
+            msg => panic!("Unexpected message {:?}", msg),
+        })
+        .collect()
+        .await
+    }
+
+    fn push_frame(client: &Client, data: Value) -> Frame {
+        Frame::create_push_frame(
+            client.encoding(),
+            client.version(),
+            false,
+            data,
+            DEFAULT_COMPRESSION_THRESHOLD,
+        )
+    }
+
+    fn request(cseq: u32) -> Frame {
+        format!(r#"{{"cseq":{},"type":"resync"}}"#, cseq)
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let (mut client, rx) = client(SlowConsumerPolicy::DropOldest);
+
+        for price in 1..=3 {
+            let frame = push_frame(&client, json!({ "price": price }));
+            client.send_msg(frame).await.unwrap();
+        }
+
+        let payloads: Vec<Value> = written(client, rx)
+            .await
+            .iter()
+            .map(|frame| frame["payload"].clone())
+            .collect();
+        assert_eq!(payloads, vec![r#"{"price":2}"#, r#"{"price":3}"#]);
+    }
+
+    #[tokio::test]
+    async fn coalesce() {
+        let (mut client, rx) = client(SlowConsumerPolicy::Coalesce);
+
+        for price in 1..=2 {
+            let frame = push_frame(&client, json!({ "price": price }));
+            client.send_msg(frame).await.unwrap();
+        }
+        client.set_last_message(json!({ "price": 3 }));
+        let response = Frame::create_data_frame(
+            &request(7),
+            client.encoding(),
+            client.version(),
+            false,
+            json!({ "price": 3 }),
+            DEFAULT_COMPRESSION_THRESHOLD,
+        );
+        client.send_msg(response).await.unwrap();
+
+        let written = written(client, rx).await;
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0]["type"], "data");
+        assert_eq!(written[0]["cseq"], 7);
+        assert_eq!(written[0]["reset"], true);
+        assert_eq!(written[0]["payload"], r#"{"price":3}"#);
+    }
+
+    #[tokio::test]
+    async fn coalesce_bound() {
+        let (mut client, rx) = client(SlowConsumerPolicy::Coalesce);
+        client.set_last_message(json!({ "price": 1 }));
+
+        for cseq in 1..=2 {
+            client
+                .send_msg(Frame::create_ok_frame(&request(cseq)))
+                .await
+                .unwrap();
+        }
+        // snapshot takes the only slot above capacity
+        let frame = push_frame(&client, json!({ "price": 1 }));
+        client.send_msg(frame).await.unwrap();
+        // other frames replace the oldest queued frame
+        for cseq in 3..=4 {
+            client
+                .send_msg(Frame::create_ok_frame(&request(cseq)))
+                .await
+                .unwrap();
+        }
+
+        let written: Vec<(Value, Value)> = written(client, rx)
+            .await
+            .iter()
+            .map(|frame| (frame["type"].clone(), frame["cseq"].clone()))
+            .collect();
+        assert_eq!(
+            written,
+            vec![
+                (json!("ok"), json!(3)),
+                (json!("ok"), json!(4)),
+                (json!("data"), json!(0)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnect() {
+        let (mut client, rx) = client(SlowConsumerPolicy::Disconnect);
+
+        for price in 1..=3 {
+            let frame = push_frame(&client, json!({ "price": price }));
+            client.send_msg(frame).await.unwrap();
+        }
+        let frame = push_frame(&client, json!({ "price": 4 }));
+        assert!(client.send_msg(frame).await.is_err());
+
+        assert_eq!(
+            written(client, rx).await,
+            vec![json!({ "close": u16::from(CloseCode::Policy), "reason": "Client is too slow" })]
+        );
+    }
+
+    #[test]
+    fn unsubscribe_pattern() {
+        let mut session = Session::new();
+        for name in &["prices.eu", "prices.us", "prices.asia"] {
+            let channel: Arc<dyn Channel> = Arc::new(StateChannel::new(*name));
+            session.channels.insert(channel);
+        }
+        session.explicit.insert("prices.eu".to_string());
+        session.patterns.insert(ChannelPattern::new("prices.*"));
+        session.patterns.insert(ChannelPattern::new("prices.a*"));
+
+        let mut unsubscribed = session.unsubscribe_pattern(&ChannelPattern::new("prices.*"));
+        unsubscribed.sort();
+        assert_eq!(unsubscribed, vec!["prices.us"]);
+
+        let unsubscribed = session.unsubscribe_pattern(&ChannelPattern::new("prices.a*"));
+        assert_eq!(unsubscribed, vec!["prices.asia"]);
+
+        let names: Vec<&str> = session.channels.iter().map(|c| c.name()).collect();
+        assert_eq!(names, vec!["prices.eu"]);
+    }
+}
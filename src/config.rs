@@ -0,0 +1,807 @@
+use crate::frame::DEFAULT_COMPRESSION_THRESHOLD;
+use crate::listener::{self, ListenAddr};
+use crate::outbox::{OutboxConfig, SlowConsumerPolicy};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Server configuration
+///
+/// Read from TOML file, every setting can be overridden with env variable named after it
+/// (see README). Settings missing in both fall back to defaults.
+///
+/// ```toml
+/// listeners = ["tcp://127.0.0.1:8080", "unix:///run/websocket.sock"]
+///
+/// [database]
+/// sqlite_path = "sqlite://state.db"
+/// pool_size = 5
+///
+/// [channels]
+/// rooms = ["lobby"]
+///
+/// [limits]
+/// outbox_capacity = 256
+///
+/// [compression]
+/// threshold = 1000
+///
+/// [auth]
+/// jwt_secret = "change-me"
+/// ```
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// addresses of listeners, at least one
+    pub listeners: Vec<ListenAddr>,
+
+    /// state backend
+    pub database: DatabaseConfig,
+
+    /// channels served at startup
+    pub channels: ChannelsConfig,
+
+    /// timeouts and queue sizes
+    pub limits: LimitsConfig,
+
+    /// compression of data frames
+    pub compression: CompressionConfig,
+
+    /// authentication and access control
+    pub auth: AuthConfig,
+
+    /// TLS certificate, TCP listeners serve `wss://` when set
+    pub tls: TlsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listeners: vec![ListenAddr::Tcp("127.0.0.1:8080".into())],
+            database: DatabaseConfig::default(),
+            channels: ChannelsConfig::default(),
+            limits: LimitsConfig::default(),
+            compression: CompressionConfig::default(),
+            auth: AuthConfig::default(),
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+/// State backend configuration
+///
+/// Exactly one database has to be set, unless memory backend is chosen explicitly
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// kind of backend, inferred from the database set if missing
+    pub backend: Option<BackendKind>,
+
+    /// path to sqlite database
+    pub sqlite_path: Option<String>,
+
+    /// url of Postgres database
+    pub postgres_url: Option<String>,
+
+    /// maximal number of database connections
+    pub pool_size: u32,
+
+    /// how often sqlite changelog is checked, in milliseconds
+    pub change_feed_interval: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            backend: None,
+            sqlite_path: None,
+            postgres_url: None,
+            pool_size: 5,
+            change_feed_interval: 50,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    /// Returns kind of configured backend
+    ///
+    /// Fails if no database is set and memory backend wasn't chosen, or if settings contradict
+    /// each other.
+    pub fn backend_kind(&self) -> Result<BackendKind> {
+        let configured = match (&self.sqlite_path, &self.postgres_url) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "database: only one of sqlite_path and postgres_url can be set"
+                ))
+            }
+            (Some(_), None) => Some(BackendKind::Sqlite),
+            (None, Some(_)) => Some(BackendKind::Postgres),
+            (None, None) => None,
+        };
+
+        match (self.backend, configured) {
+            (None, Some(kind)) => Ok(kind),
+            (Some(kind), Some(configured)) if kind == configured => Ok(kind),
+            (Some(BackendKind::Memory), None) => Ok(BackendKind::Memory),
+            (None, None) => Err(anyhow!(
+                "database: set sqlite_path or postgres_url, or choose backend = \"memory\""
+            )),
+            (Some(BackendKind::Memory), Some(_)) => Err(anyhow!(
+                "database: memory backend can't be combined with sqlite_path or postgres_url"
+            )),
+            (Some(BackendKind::Sqlite), _) => {
+                Err(anyhow!("database: sqlite backend requires sqlite_path"))
+            }
+            (Some(BackendKind::Postgres), _) => {
+                Err(anyhow!("database: postgres backend requires postgres_url"))
+            }
+        }
+    }
+}
+
+/// Kind of state backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// sqlite database pointed by `sqlite_path`
+    Sqlite,
+
+    /// Postgres database pointed by `postgres_url`
+    Postgres,
+
+    /// everything is kept in memory and lost on restart
+    Memory,
+}
+
+impl FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sqlite" => Ok(BackendKind::Sqlite),
+            "postgres" => Ok(BackendKind::Postgres),
+            "memory" => Ok(BackendKind::Memory),
+            _ => Err(anyhow!(
+                "Unknown backend '{}', expected sqlite, postgres or memory",
+                s
+            )),
+        }
+    }
+}
+
+/// Timeouts and queue sizes
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// how long sessions of disconnected clients are kept, in seconds
+    pub session_grace_period: u64,
+
+    /// deadline of graceful shutdown, in seconds
+    pub shutdown_timeout: u64,
+
+    /// number of malformed messages in a row, which closes connection
+    pub max_invalid_messages: usize,
+
+    /// maximal number of events waiting for the broker
+    pub broker_queue_size: usize,
+
+    /// maximal number of frames queued per client
+    pub outbox_capacity: usize,
+
+    /// action taken when client's queue is full
+    pub slow_consumer_policy: SlowConsumerPolicy,
+
+    /// how long channels can take to extract their data, in milliseconds
+    pub extraction_timeout: u64,
+
+    /// how long extracted data is shared between clients, in milliseconds
+    pub cache_ttl: u64,
+
+    /// maximal number of room messages replayed on subscribe
+    pub max_history: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            session_grace_period: 30,
+            shutdown_timeout: 10,
+            max_invalid_messages: 10,
+            broker_queue_size: 1024,
+            outbox_capacity: OutboxConfig::default().capacity,
+            slow_consumer_policy: OutboxConfig::default().policy,
+            extraction_timeout: 5000,
+            cache_ttl: 100,
+            max_history: 100,
+        }
+    }
+}
+
+/// Compression of data frames
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// payloads longer than this number of bytes are compressed
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+/// Authentication and access control
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// secret of HS256 signed JWT tokens, clients are not authenticated if not set
+    pub jwt_secret: Option<String>,
+
+    /// path to access rules (see `AclConfig`), everything is allowed if not set
+    pub acl_config: Option<PathBuf>,
+}
+
+/// TLS certificate and private key
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// path to PEM file with certificate chain
+    pub cert_path: Option<PathBuf>,
+
+    /// path to PEM file with private key
+    pub key_path: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reads configuration from TOML file, applies env overrides and validates the result
+    ///
+    /// # Arguments:
+    /// * `path` - path to config file, defaults are used if not given
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let mut config = match path {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Reads configuration from TOML file
+    ///
+    /// # Arguments:
+    /// * `path` - path to config file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+
+        toml::from_str(&content).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    /// Overrides settings with env variables
+    ///
+    /// # Arguments:
+    /// * `env` - returns value of env variable
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, env: F) -> Result<()> {
+        if let Some(addrs) = env("SOCKET_ADDR") {
+            self.listeners = listener::parse_addrs(&addrs).context("Invalid SOCKET_ADDR")?;
+        }
+
+        if let Some(path) = env("CHANNELS_CONFIG") {
+            self.channels = ChannelsConfig::from_file(path)?;
+        }
+
+        let database = &mut self.database;
+        override_option(&mut database.backend, "DB_BACKEND", &env)?;
+        override_option(&mut database.sqlite_path, "SQLITE_PATH", &env)?;
+        override_option(&mut database.postgres_url, "POSTGRES_URL", &env)?;
+        override_value(&mut database.pool_size, "DB_POOL_SIZE", &env)?;
+        override_value(
+            &mut database.change_feed_interval,
+            "CHANGE_FEED_INTERVAL",
+            &env,
+        )?;
+
+        let limits = &mut self.limits;
+        override_value(
+            &mut limits.session_grace_period,
+            "SESSION_GRACE_PERIOD",
+            &env,
+        )?;
+        override_value(&mut limits.shutdown_timeout, "SHUTDOWN_TIMEOUT", &env)?;
+        override_value(
+            &mut limits.max_invalid_messages,
+            "MAX_INVALID_MESSAGES",
+            &env,
+        )?;
+        override_value(&mut limits.broker_queue_size, "BROKER_QUEUE_SIZE", &env)?;
+        override_value(&mut limits.outbox_capacity, "OUTBOX_CAPACITY", &env)?;
+        override_value(
+            &mut limits.slow_consumer_policy,
+            "SLOW_CONSUMER_POLICY",
+            &env,
+        )?;
+        override_value(&mut limits.extraction_timeout, "EXTRACTION_TIMEOUT", &env)?;
+        override_value(&mut limits.cache_ttl, "CACHE_TTL", &env)?;
+        override_value(&mut limits.max_history, "MAX_HISTORY", &env)?;
+
+        override_value(
+            &mut self.compression.threshold,
+            "COMPRESSION_THRESHOLD",
+            &env,
+        )?;
+
+        override_option(&mut self.auth.jwt_secret, "JWT_SECRET", &env)?;
+        override_option(&mut self.auth.acl_config, "ACL_CONFIG", &env)?;
+        override_option(&mut self.tls.cert_path, "TLS_CERT_PATH", &env)?;
+        override_option(&mut self.tls.key_path, "TLS_KEY_PATH", &env)?;
+
+        Ok(())
+    }
+
+    /// Checks consistency of settings
+    pub fn validate(&self) -> Result<()> {
+        if self.listeners.is_empty() {
+            return Err(anyhow!(
+                "listeners: at least one listener has to be configured"
+            ));
+        }
+
+        let database = &self.database;
+        let positive = [
+            ("database.pool_size", database.pool_size as u64),
+            (
+                "database.change_feed_interval",
+                database.change_feed_interval,
+            ),
+            ("limits.shutdown_timeout", self.limits.shutdown_timeout),
+            (
+                "limits.max_invalid_messages",
+                self.limits.max_invalid_messages as u64,
+            ),
+            (
+                "limits.broker_queue_size",
+                self.limits.broker_queue_size as u64,
+            ),
+            ("limits.outbox_capacity", self.limits.outbox_capacity as u64),
+            ("limits.extraction_timeout", self.limits.extraction_timeout),
+            ("limits.max_history", u64::from(self.limits.max_history)),
+        ];
+
+        for (name, value) in positive.iter() {
+            if *value == 0 {
+                return Err(anyhow!("{}: has to be greater than 0", name));
+            }
+        }
+
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            return Err(anyhow!("tls: both cert_path and key_path have to be set"));
+        }
+
+        database.backend_kind()?;
+
+        Ok(())
+    }
+}
+
+/// Replaces value with parsed env variable, if it's set
+///
+/// # Arguments:
+/// * `value` - overridden setting
+/// * `name` - name of env variable
+/// * `env` - returns value of env variable
+fn override_value<T, F>(value: &mut T, name: &str, env: &F) -> Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+    F: Fn(&str) -> Option<String>,
+{
+    if let Some(raw) = env(name) {
+        *value = raw
+            .parse()
+            .map_err(|e| anyhow!("Invalid {} '{}': {}", name, raw, e))?;
+    }
+
+    Ok(())
+}
+
+/// Replaces optional value with parsed env variable, if it's set
+///
+/// # Arguments:
+/// * `value` - overridden setting
+/// * `name` - name of env variable
+/// * `env` - returns value of env variable
+fn override_option<T, F>(value: &mut Option<T>, name: &str, env: &F) -> Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+    F: Fn(&str) -> Option<String>,
+{
+    if let Some(raw) = env(name) {
+        let parsed = raw
+            .parse()
+            .map_err(|e| anyhow!("Invalid {} '{}': {}", name, raw, e))?;
+        *value = Some(parsed);
+    }
+
+    Ok(())
+}
+
+/// Command line arguments
+#[derive(Debug, Default, PartialEq)]
+pub struct CliArgs {
+    /// path to config file
+    pub config: Option<PathBuf>,
+
+    /// only validate configuration and exit
+    pub check_config: bool,
+}
+
+impl CliArgs {
+    /// Parses command line arguments
+    ///
+    /// # Arguments:
+    /// * `args` - arguments without program name
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<CliArgs> {
+        let mut cli_args = CliArgs::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" | "-c" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| anyhow!("{} requires a path to config file", arg))?;
+                    cli_args.config = Some(path.into());
+                }
+                "--check-config" => cli_args.check_config = true,
+                _ => {
+                    return Err(anyhow!(
+                    "Unknown argument '{}', usage: websocket [--config <path>] [--check-config]",
+                    arg
+                ))
+                }
+            }
+        }
+
+        Ok(cli_args)
+    }
+}
+
+/// Channels configuration
+///
+/// ```toml
+/// # register every row of `state` table at startup
+/// discover = true
+/// # rows of `state` table served as channels
+/// state = ["13", "prices"]
+/// # chat rooms with message history
+/// rooms = ["lobby"]
+/// ```
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelsConfig {
+    /// register every row of `state` table at startup
+    pub discover: bool,
+
+    /// rows of `state` table served as channels
+    pub state: Vec<String>,
+
+    /// chat rooms with message history
+    pub rooms: Vec<String>,
+}
+
+impl Default for ChannelsConfig {
+    fn default() -> Self {
+        ChannelsConfig {
+            discover: true,
+            state: Vec::new(),
+            rooms: Vec::new(),
+        }
+    }
+}
+
+impl ChannelsConfig {
+    /// Reads channels configuration from TOML file
+    ///
+    /// # Arguments:
+    /// * `path` - path to config file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ChannelsConfig> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read channels config {}", path.display()))?;
+
+        toml::from_str(&content)
+            .with_context(|| format!("Invalid channels config {}", path.display()))
+    }
+}
+
+/// Channel access rules
+///
+/// ```toml
+/// [[rule]]
+/// # rule applies to listed subjects and holders of listed roles, or to everyone if both are empty
+/// subjects = ["alice"]
+/// roles = ["admin"]
+/// # channels or channel patterns which can be subscribed to
+/// subscribe = ["prices.*", "lobby"]
+/// # channels or channel patterns which accept publishing
+/// publish = ["lobby"]
+/// ```
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    /// access rules, access is granted if any rule allows it
+    #[serde(rename = "rule")]
+    pub rules: Vec<AclRule>,
+}
+
+/// Single access rule
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclRule {
+    /// subjects of authenticated clients
+    pub subjects: Vec<String>,
+
+    /// roles of authenticated clients
+    pub roles: Vec<String>,
+
+    /// channels or channel patterns which can be subscribed to
+    pub subscribe: Vec<String>,
+
+    /// channels or channel patterns which accept publishing
+    pub publish: Vec<String>,
+}
+
+impl AclConfig {
+    /// Reads access rules from TOML file
+    ///
+    /// # Arguments:
+    /// * `path` - path to config file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<AclConfig> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read ACL config {}", path.display()))?;
+
+        toml::from_str(&content).with_context(|| format!("Invalid ACL config {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config() {
+        let config: Config = toml::from_str(
+            r#"
+            listeners = ["tcp://0.0.0.0:8080", "unix:///run/websocket.sock"]
+
+            [database]
+            postgres_url = "postgres://localhost/websocket"
+
+            [channels]
+            rooms = ["lobby"]
+
+            [limits]
+            slow_consumer_policy = "drop-oldest"
+
+            [compression]
+            threshold = 4096
+
+            [auth]
+            jwt_secret = "change-me"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.listeners,
+            vec![
+                ListenAddr::Tcp("0.0.0.0:8080".into()),
+                ListenAddr::Unix("/run/websocket.sock".into()),
+            ]
+        );
+        assert_eq!(
+            config.database.postgres_url.as_deref(),
+            Some("postgres://localhost/websocket")
+        );
+        assert_eq!(config.database.pool_size, 5);
+        assert_eq!(config.channels.rooms, vec!["lobby".to_string()]);
+        assert_eq!(
+            config.limits.slow_consumer_policy,
+            SlowConsumerPolicy::DropOldest
+        );
+        assert_eq!(config.limits.outbox_capacity, 64);
+        assert_eq!(config.compression.threshold, 4096);
+        assert_eq!(config.auth.jwt_secret.as_deref(), Some("change-me"));
+        assert!(config.validate().is_ok());
+
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config, Config::default());
+
+        let error = toml::from_str::<Config>(r#"listeners = ["udp://127.0.0.1:8080"]"#);
+        assert!(error
+            .unwrap_err()
+            .to_string()
+            .contains("udp://127.0.0.1:8080"));
+
+        assert!(toml::from_str::<Config>("[limits]\nqueue = 1").is_err());
+    }
+
+    #[test]
+    fn env_overrides() {
+        let env = |name: &str| match name {
+            "SOCKET_ADDR" => Some("unix:///tmp/websocket.sock".to_string()),
+            "SQLITE_PATH" => Some("sqlite://state.db".to_string()),
+            "OUTBOX_CAPACITY" => Some("256".to_string()),
+            "SLOW_CONSUMER_POLICY" => Some("disconnect".to_string()),
+            _ => None,
+        };
+
+        let mut config = Config::default();
+        config.apply_env(env).unwrap();
+
+        assert_eq!(
+            config.listeners,
+            vec![ListenAddr::Unix("/tmp/websocket.sock".into())]
+        );
+        assert_eq!(
+            config.database.sqlite_path.as_deref(),
+            Some("sqlite://state.db")
+        );
+        assert_eq!(config.limits.outbox_capacity, 256);
+        assert_eq!(
+            config.limits.slow_consumer_policy,
+            SlowConsumerPolicy::Disconnect
+        );
+        assert_eq!(config.limits.broker_queue_size, 1024);
+
+        let error = Config::default()
+            .apply_env(|name| match name {
+                "CACHE_TTL" => Some("soon".to_string()),
+                _ => None,
+            })
+            .unwrap_err();
+        assert!(error.to_string().contains("CACHE_TTL"));
+    }
+
+    #[test]
+    fn validation() {
+        let mut config = Config::default();
+        config.listeners.clear();
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.database.sqlite_path = Some("sqlite://state.db".into());
+        config.database.postgres_url = Some("postgres://localhost/websocket".into());
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.limits.broker_queue_size = 0;
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("limits.broker_queue_size"));
+
+        let mut config = Config::default();
+        config.limits.max_invalid_messages = 0;
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("limits.max_invalid_messages"));
+
+        let mut config = Config::default();
+        config.limits.shutdown_timeout = 0;
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("limits.shutdown_timeout"));
+
+        let mut config = Config::default();
+        config.tls.cert_path = Some("cert.pem".into());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn backend_kind() {
+        let mut database = DatabaseConfig::default();
+        assert!(database.backend_kind().is_err());
+
+        database.backend = Some(BackendKind::Memory);
+        assert_eq!(database.backend_kind().unwrap(), BackendKind::Memory);
+
+        database.sqlite_path = Some("sqlite://state.db".into());
+        assert!(database.backend_kind().is_err());
+
+        database.backend = None;
+        assert_eq!(database.backend_kind().unwrap(), BackendKind::Sqlite);
+
+        database.backend = Some(BackendKind::Postgres);
+        assert!(database.backend_kind().is_err());
+
+        let database: DatabaseConfig = toml::from_str(r#"backend = "memory""#).unwrap();
+        assert_eq!(database.backend_kind().unwrap(), BackendKind::Memory);
+        assert!("redis".parse::<BackendKind>().is_err());
+    }
+
+    #[test]
+    fn cli_args() {
+        let args = |args: &[&str]| CliArgs::parse(args.iter().map(|arg| arg.to_string()));
+
+        assert_eq!(args(&[]).unwrap(), CliArgs::default());
+        assert_eq!(
+            args(&["--config", "websocket.toml", "--check-config"]).unwrap(),
+            CliArgs {
+                config: Some("websocket.toml".into()),
+                check_config: true,
+            }
+        );
+        assert!(args(&["--config"]).is_err());
+        assert!(args(&["--verbose"]).is_err());
+    }
+
+    #[test]
+    fn channels_config() {
+        let config: ChannelsConfig = toml::from_str(
+            r#"
+            discover = false
+            state = ["13", "prices"]
+            rooms = ["lobby"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            ChannelsConfig {
+                discover: false,
+                state: vec!["13".to_string(), "prices".to_string()],
+                rooms: vec!["lobby".to_string()],
+            }
+        );
+
+        let config: ChannelsConfig = toml::from_str("").unwrap();
+        assert_eq!(config, ChannelsConfig::default());
+
+        assert!(toml::from_str::<ChannelsConfig>("sql = []").is_err());
+    }
+
+    #[test]
+    fn acl_config() {
+        let config: AclConfig = toml::from_str(
+            r#"
+            [[rule]]
+            roles = ["admin"]
+            subscribe = ["prices.*"]
+            publish = ["lobby"]
+
+            [[rule]]
+            subscribe = ["lobby"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            AclConfig {
+                rules: vec![
+                    AclRule {
+                        roles: vec!["admin".to_string()],
+                        subscribe: vec!["prices.*".to_string()],
+                        publish: vec!["lobby".to_string()],
+                        ..AclRule::default()
+                    },
+                    AclRule {
+                        subscribe: vec!["lobby".to_string()],
+                        ..AclRule::default()
+                    },
+                ],
+            }
+        );
+
+        assert!(toml::from_str::<AclConfig>("[[rule]]\nread = []").is_err());
+    }
+}
@@ -1,11 +1,14 @@
-use anyhow::{anyhow, Error};
+use crate::utils::{create_json_snapshot, create_merge_patch};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{convert::TryFrom, str::FromStr};
+use std::{convert::TryFrom, fmt, str::FromStr};
 use tungstenite::Message;
 
+/// Payloads of data frames longer than this number of bytes are compressed by default
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1000;
+
 /// Communication frame
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Frame {
     /// sequence code
     cseq: u32,
@@ -16,13 +19,20 @@ pub struct Frame {
 }
 
 /// Type of payload
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum FrameData {
     /// Subscribe request
     ///
-    /// contains list of channels that client wants subscribe to
-    Subscribe { channels: Vec<String> },
+    /// contains list of channels that client wants subscribe to and optionally
+    /// the delta encoding of subsequent data frames and range of past messages to be replayed
+    Subscribe {
+        channels: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<DeltaEncoding>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        history: Option<HistoryQuery>,
+    },
 
     /// Unsubscribe request
     ///
@@ -34,6 +44,66 @@ pub enum FrameData {
     /// client signals that is ready to data transfer
     Ready,
 
+    /// Resync request
+    ///
+    /// client lost track of data frames (version gap or failed patch) and requests full snapshot
+    Resync,
+
+    /// Resume request
+    ///
+    /// client reconnected and wants to take over its previous session. Contains session token
+    /// from welcome frame and version of last data frame applied by client
+    Resume { session: String, version: u64 },
+
+    /// Publish request
+    ///
+    /// client sends a message to the channel, accepted messages are delivered to all subscribers
+    Publish { channel: String, payload: Value },
+
+    /// Channel message
+    ///
+    /// message published to the channel, sent by server to subscribers
+    Message { channel: String, payload: Value },
+
+    /// Identify request
+    ///
+    /// client sets display name, which identifies it in presence of channels
+    Identify { name: String },
+
+    /// Presence frame
+    ///
+    /// current members of the channel, sent to client subscribing to `presence:<channel>`
+    Presence {
+        channel: String,
+        members: Vec<String>,
+    },
+
+    /// Join frame
+    ///
+    /// member joined the channel, sent to clients subscribing to `presence:<channel>`. Contains
+    /// display name of the member if it chose any
+    Join {
+        channel: String,
+        member: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+
+    /// Leave frame
+    ///
+    /// member left the channel, sent to clients subscribing to `presence:<channel>`
+    Leave {
+        channel: String,
+        member: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+
+    /// Welcome frame
+    ///
+    /// sent by server right after connection is established, contains session token
+    Welcome { session: String },
+
     /// Ok Frame
     ///
     /// a status frame - server sucessfully processed the request
@@ -41,13 +111,161 @@ pub enum FrameData {
 
     /// Err frame
     ///
-    /// a status frame - server failed to processed the request. Reuses http codes
-    Err { code: u32, reason: String },
+    /// a status frame - server failed to processed the request. Reuses http codes.
+    /// Errors concerning single channel contain its name
+    Err {
+        code: u32,
+        reason: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+    },
 
     /// Data message
     ///
-    /// data sent by server to client
-    Data { compressed: bool, payload: String },
+    /// data sent by server to client - an incremental diff of observed channels, encoded as
+    /// requested by client. Version grows by one with every data frame sent to the client,
+    /// so any gap means that client has to request a resync. Reset frames carry full snapshot,
+    /// which replaces client's data instead of being applied to it
+    Data {
+        compressed: bool,
+        encoding: DeltaEncoding,
+        version: u64,
+        #[serde(default)]
+        reset: bool,
+        payload: String,
+    },
+
+    /// Frame of unrecognized type
+    #[serde(other)]
+    Unknown,
+}
+
+impl FrameData {
+    /// Checks if frame of this type can be sent by client
+    pub fn is_client_frame(&self) -> bool {
+        use FrameData::*;
+
+        match self {
+            Subscribe { .. }
+            | Unsubscribe { .. }
+            | Ready
+            | Resync
+            | Resume { .. }
+            | Publish { .. }
+            | Identify { .. } => true,
+            Message { .. }
+            | Presence { .. }
+            | Join { .. }
+            | Leave { .. }
+            | Welcome { .. }
+            | Ok
+            | Err { .. }
+            | Data { .. }
+            | Unknown => false,
+        }
+    }
+}
+
+/// Range of past channel messages replayed on subscribe
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HistoryQuery {
+    /// last N messages - `{"last": 10}`
+    Last(u32),
+
+    /// all messages with id greater than given one - `{"since": 42}`
+    Since(i64),
+}
+
+/// Reason of rejecting client's message
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    /// binary (or other non-text) message
+    NotText,
+
+    /// message is not a valid JSON frame
+    BadJson { cseq: u32, reason: String },
+
+    /// `cseq` is missing or is not a valid number
+    MissingCseq,
+
+    /// `type` is missing or is not accepted from clients
+    UnknownType { cseq: u32, type_name: String },
+}
+
+impl FrameError {
+    /// Returns code of err frame, reuses http codes
+    pub fn code(&self) -> u32 {
+        match self {
+            FrameError::NotText => 415,
+            FrameError::BadJson { .. } => 400,
+            FrameError::MissingCseq => 422,
+            FrameError::UnknownType { .. } => 501,
+        }
+    }
+
+    /// Returns cseq of rejected message, `0` if unknown
+    pub fn cseq(&self) -> u32 {
+        match self {
+            FrameError::BadJson { cseq, .. } | FrameError::UnknownType { cseq, .. } => *cseq,
+            FrameError::NotText | FrameError::MissingCseq => 0,
+        }
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::NotText => write!(f, "Only text messages are supported"),
+            FrameError::BadJson { reason, .. } => write!(f, "Malformed frame: {}", reason),
+            FrameError::MissingCseq => write!(f, "Missing or invalid cseq"),
+            FrameError::UnknownType { type_name, .. } => {
+                write!(f, "Unknown frame type: '{}'", type_name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Encoding of incremental diff carried by data frames
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DeltaEncoding {
+    /// Partial object, removed keys are marked with `{"$deleted": true}`, values which would be
+    /// taken for such a marker are sent as `{"$escaped": value}`
+    #[default]
+    Snapshot,
+
+    /// JSON Patch (RFC 6902)
+    JsonPatch,
+
+    /// JSON Merge Patch (RFC 7396)
+    ///
+    /// `null` values are not representable - these mean removal of the key
+    MergePatch,
+}
+
+impl DeltaEncoding {
+    /// Creates incremental diff of two json documents
+    ///
+    /// # Arguments:
+    /// * `old_state` - document already known by client
+    /// * `new_state` - new document
+    pub fn encode(self, old_state: &Value, new_state: &Value) -> Value {
+        match self {
+            DeltaEncoding::Snapshot => {
+                let mut snapshot = old_state.clone();
+                create_json_snapshot(&mut snapshot, new_state);
+                snapshot
+            }
+            DeltaEncoding::JsonPatch => {
+                let patch = json_patch::diff(old_state, new_state);
+                serde_json::to_value(patch).expect("No reason to fail")
+            }
+            DeltaEncoding::MergePatch => create_merge_patch(old_state, new_state),
+        }
+    }
 }
 
 impl Frame {
@@ -86,7 +304,127 @@ impl Frame {
 
         Frame {
             cseq,
-            data: FrameData::Err { code, reason },
+            data: FrameData::Err {
+                code,
+                reason,
+                channel: None,
+            },
+        }
+    }
+
+    /// Creates "welcome" frame - greets client with session token
+    ///
+    /// # Arguments:
+    /// * `session` - session token
+    pub fn create_welcome_frame<S: Into<String>>(session: S) -> Frame {
+        Frame {
+            cseq: 0,
+            data: FrameData::Welcome {
+                session: session.into(),
+            },
+        }
+    }
+
+    /// Creates "err" frame for rejected client message
+    ///
+    /// # Arguments:
+    /// * `error` - reason of rejection
+    pub fn create_rejection_frame(error: &FrameError) -> Frame {
+        Frame {
+            cseq: error.cseq(),
+            data: FrameData::Err {
+                code: error.code(),
+                reason: error.to_string(),
+                channel: None,
+            },
+        }
+    }
+
+    /// Creates "err" frame concerning single channel
+    ///
+    /// # Arguments:
+    /// * `cseq` - cseq of request, `0` for server-initiated frames
+    /// * `channel` - name of channel
+    /// * `code` - code from HTTP range
+    /// * `reason` - reason of error
+    pub fn create_channel_err_frame<S: Into<String>>(
+        cseq: u32,
+        channel: &str,
+        code: u32,
+        reason: S,
+    ) -> Frame {
+        Frame {
+            cseq,
+            data: FrameData::Err {
+                code,
+                reason: reason.into(),
+                channel: Some(channel.to_string()),
+            },
+        }
+    }
+
+    /// Creates channel message frame pushed to subscribers
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    /// * `payload` - published message
+    pub fn create_message_frame(channel: &str, payload: Value) -> Frame {
+        Frame {
+            cseq: 0,
+            data: FrameData::Message {
+                channel: channel.to_string(),
+                payload,
+            },
+        }
+    }
+
+    /// Creates presence frame with current members of the channel
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    /// * `members` - names of members
+    pub fn create_presence_frame(channel: &str, members: Vec<String>) -> Frame {
+        Frame {
+            cseq: 0,
+            data: FrameData::Presence {
+                channel: channel.to_string(),
+                members,
+            },
+        }
+    }
+
+    /// Creates join or leave frame
+    ///
+    /// # Arguments:
+    /// * `channel` - name of channel
+    /// * `member` - name of member
+    /// * `name` - display name of member
+    /// * `joined` - member joined (`true`) or left (`false`) the channel
+    pub fn create_presence_change_frame(
+        channel: &str,
+        member: &str,
+        name: Option<&str>,
+        joined: bool,
+    ) -> Frame {
+        let channel = channel.to_string();
+        let member = member.to_string();
+        let name = name.map(str::to_string);
+
+        Frame {
+            cseq: 0,
+            data: if joined {
+                FrameData::Join {
+                    channel,
+                    member,
+                    name,
+                }
+            } else {
+                FrameData::Leave {
+                    channel,
+                    member,
+                    name,
+                }
+            },
         }
     }
 
@@ -94,12 +432,60 @@ impl Frame {
     ///
     /// # Arguments:
     /// * `client_frame` - request frame
+    /// * `encoding` - encoding of payload
+    /// * `version` - version of client's data
+    /// * `reset` - payload is full snapshot replacing client's data
     /// * `data` - payload to be sent
-    pub fn create_data_frame(client_frame: &Frame, data: Value) -> Frame {
-        let cseq = client_frame.cseq;
+    /// * `compression_threshold` - payloads longer than this number of bytes are compressed
+    pub fn create_data_frame(
+        client_frame: &Frame,
+        encoding: DeltaEncoding,
+        version: u64,
+        reset: bool,
+        data: Value,
+        compression_threshold: usize,
+    ) -> Frame {
+        Self::data_frame(
+            client_frame.cseq,
+            encoding,
+            version,
+            reset,
+            data,
+            compression_threshold,
+        )
+    }
+
+    /// Creates data frame pushed by server without prior request
+    ///
+    /// Server-initiated frames always carry cseq `0`
+    ///
+    /// # Arguments:
+    /// * `encoding` - encoding of payload
+    /// * `version` - version of client's data
+    /// * `reset` - payload is full snapshot replacing client's data
+    /// * `data` - payload to be sent
+    /// * `compression_threshold` - payloads longer than this number of bytes are compressed
+    pub fn create_push_frame(
+        encoding: DeltaEncoding,
+        version: u64,
+        reset: bool,
+        data: Value,
+        compression_threshold: usize,
+    ) -> Frame {
+        Self::data_frame(0, encoding, version, reset, data, compression_threshold)
+    }
+
+    fn data_frame(
+        cseq: u32,
+        encoding: DeltaEncoding,
+        version: u64,
+        reset: bool,
+        data: Value,
+        compression_threshold: usize,
+    ) -> Frame {
         let mut data = data.to_string();
 
-        let compressed = data.len() > 1000;
+        let compressed = data.len() > compression_threshold;
 
         if compressed {
             data = lz_string::compress_uri(&data).unwrap();
@@ -109,6 +495,9 @@ impl Frame {
             cseq,
             data: FrameData::Data {
                 compressed,
+                encoding,
+                version,
+                reset,
                 payload: data,
             },
         }
@@ -123,23 +512,50 @@ impl Frame {
 }
 
 impl FromStr for Frame {
-    type Err = Error;
+    type Err = FrameError;
 
     fn from_str(message: &str) -> Result<Self, Self::Err> {
-        let frame: Frame =
-            serde_json::from_str(&message).map_err(|e| anyhow!("Deserialize error!\n\t{}", e))?;
+        let value: Value = serde_json::from_str(message).map_err(|e| FrameError::BadJson {
+            cseq: 0,
+            reason: e.to_string(),
+        })?;
+
+        let cseq = value
+            .get("cseq")
+            .and_then(Value::as_u64)
+            .and_then(|cseq| u32::try_from(cseq).ok())
+            .ok_or(FrameError::MissingCseq)?;
+
+        let type_name = match value.get("type").and_then(Value::as_str) {
+            Some(type_name) => type_name.to_string(),
+            None => {
+                return Err(FrameError::UnknownType {
+                    cseq,
+                    type_name: String::new(),
+                })
+            }
+        };
+
+        let frame: Frame = serde_json::from_value(value).map_err(|e| FrameError::BadJson {
+            cseq,
+            reason: e.to_string(),
+        })?;
+
+        if !frame.data.is_client_frame() {
+            return Err(FrameError::UnknownType { cseq, type_name });
+        }
 
         Ok(frame)
     }
 }
 
 impl TryFrom<&tungstenite::Message> for Frame {
-    type Error = Error;
+    type Error = FrameError;
 
     fn try_from(message: &Message) -> Result<Self, Self::Error> {
         let message = match message {
             Message::Text(txt) => txt.trim(),
-            _ => return Err(anyhow!("Expected Message::Text")),
+            _ => return Err(FrameError::NotText),
         };
 
         let frame = message.parse()?;
@@ -161,6 +577,26 @@ mod test {
             cseq: 1,
             data: FrameData::Subscribe {
                 channels: vec!["news".to_string()],
+                encoding: None,
+                history: None,
+            },
+        };
+
+        let msg = json.parse::<Frame>().unwrap();
+
+        assert_eq!(msg, expected_msg);
+    }
+
+    #[test]
+    fn subscribe_with_encoding_deserialize() {
+        let json = r#"{"cseq":1,"type":"subscribe","channels":["news"],"encoding":"jsonPatch"}"#;
+
+        let expected_msg = Frame {
+            cseq: 1,
+            data: FrameData::Subscribe {
+                channels: vec!["news".to_string()],
+                encoding: Some(DeltaEncoding::JsonPatch),
+                history: None,
             },
         };
 
@@ -169,6 +605,42 @@ mod test {
         assert_eq!(msg, expected_msg);
     }
 
+    #[test]
+    fn subscribe_with_history_deserialize() {
+        let json = r#"{"cseq":1,"type":"subscribe","channels":["lobby"],"history":{"since":42}}"#;
+
+        let expected_msg = Frame {
+            cseq: 1,
+            data: FrameData::Subscribe {
+                channels: vec!["lobby".to_string()],
+                encoding: None,
+                history: Some(HistoryQuery::Since(42)),
+            },
+        };
+
+        assert_eq!(json.parse::<Frame>().unwrap(), expected_msg);
+    }
+
+    #[test]
+    fn encodings() {
+        let old = json!({"chan": {"a": 1, "b": 2}});
+        let new = json!({"chan": {"a": 3}});
+
+        assert_eq!(
+            DeltaEncoding::Snapshot.encode(&old, &new),
+            json!({"chan": {"a": 3, "b": {"$deleted": true}}})
+        );
+        assert_eq!(
+            DeltaEncoding::MergePatch.encode(&old, &new),
+            json!({"chan": {"a": 3, "b": null}})
+        );
+
+        let patch = DeltaEncoding::JsonPatch.encode(&old, &new);
+        let mut doc = old.clone();
+        json_patch::patch(&mut doc, &json_patch::from_value(patch).unwrap()).unwrap();
+        assert_eq!(doc, new);
+    }
+
     #[test]
     fn data_frame() {
         let data = json!({"t": "xyz"});
@@ -181,16 +653,180 @@ mod test {
             cseq: 2,
             data: FrameData::Data {
                 compressed: false,
+                encoding: DeltaEncoding::Snapshot,
+                version: 1,
+                reset: false,
                 payload: r#"{"t":"xyz"}"#.to_string(),
             },
         };
 
-        let response_frame = Frame::create_data_frame(&ready_req, data);
+        let response_frame = Frame::create_data_frame(
+            &ready_req,
+            DeltaEncoding::Snapshot,
+            1,
+            false,
+            data,
+            DEFAULT_COMPRESSION_THRESHOLD,
+        );
 
         println!("response_frame {:?}", response_frame);
         assert_eq!(response_frame, expected_frame);
     }
 
+    #[test]
+    fn push_frame() {
+        let frame = Frame::create_push_frame(
+            DeltaEncoding::MergePatch,
+            7,
+            true,
+            json!({"t": "xyz"}),
+            DEFAULT_COMPRESSION_THRESHOLD,
+        );
+
+        assert_eq!(frame.cseq(), 0);
+        assert_eq!(
+            frame.data(),
+            &FrameData::Data {
+                compressed: false,
+                encoding: DeltaEncoding::MergePatch,
+                version: 7,
+                reset: true,
+                payload: r#"{"t":"xyz"}"#.to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn resync_deserialize() {
+        let json = r#"{"cseq":3,"type":"resync"}"#;
+
+        let expected_msg = Frame {
+            cseq: 3,
+            data: FrameData::Resync,
+        };
+
+        assert_eq!(json.parse::<Frame>().unwrap(), expected_msg);
+    }
+
+    #[test]
+    fn resume_deserialize() {
+        let json = r#"{"cseq":1,"type":"resume","session":"abc","version":12}"#;
+
+        let expected_msg = Frame {
+            cseq: 1,
+            data: FrameData::Resume {
+                session: "abc".to_string(),
+                version: 12,
+            },
+        };
+
+        assert_eq!(json.parse::<Frame>().unwrap(), expected_msg);
+    }
+
+    #[test]
+    fn welcome_serialize() {
+        let json = serde_json::to_string(&Frame::create_welcome_frame("abc")).unwrap();
+
+        assert_eq!(json, r#"{"cseq":0,"type":"welcome","session":"abc"}"#);
+    }
+
+    #[test]
+    fn rejected_messages() {
+        let parse = |txt: &str| Frame::try_from(&Message::Text(txt.to_string())).unwrap_err();
+
+        assert_eq!(
+            Frame::try_from(&Message::Binary(vec![1, 2, 3])).unwrap_err(),
+            FrameError::NotText
+        );
+        assert_eq!(parse(r#"{"cseq":1,"#).code(), 400);
+        assert_eq!(parse(r#"{"type":"ready"}"#), FrameError::MissingCseq);
+        assert_eq!(
+            parse(r#"{"cseq":-1,"type":"ready"}"#),
+            FrameError::MissingCseq
+        );
+        assert_eq!(
+            parse(r#"{"cseq":2,"type":"dance"}"#),
+            FrameError::UnknownType {
+                cseq: 2,
+                type_name: "dance".to_string()
+            }
+        );
+        assert_eq!(
+            parse(r#"{"cseq":3,"type":"ok"}"#),
+            FrameError::UnknownType {
+                cseq: 3,
+                type_name: "ok".to_string()
+            }
+        );
+
+        let err = parse(r#"{"cseq":4,"type":"subscribe","channels":"13"}"#);
+        assert_eq!(err.code(), 400);
+        assert_eq!(err.cseq(), 4);
+
+        let frame = Frame::create_rejection_frame(&err);
+        assert_eq!(frame.cseq(), 4);
+    }
+
+    #[test]
+    fn channel_err_serialize() {
+        let frame = Frame::create_channel_err_frame(5, "13", 500, "db is gone");
+
+        assert_eq!(
+            serde_json::to_string(&frame).unwrap(),
+            r#"{"cseq":5,"type":"err","code":500,"reason":"db is gone","channel":"13"}"#
+        );
+    }
+
+    #[test]
+    fn publish_deserialize() {
+        let json = r#"{"cseq":4,"type":"publish","channel":"chat","payload":{"text":"hi"}}"#;
+
+        let expected_msg = Frame {
+            cseq: 4,
+            data: FrameData::Publish {
+                channel: "chat".to_string(),
+                payload: json!({"text": "hi"}),
+            },
+        };
+
+        assert_eq!(json.parse::<Frame>().unwrap(), expected_msg);
+    }
+
+    #[test]
+    fn identify_deserialize() {
+        let json = r#"{"cseq":5,"type":"identify","name":"alice"}"#;
+
+        let expected_msg = Frame {
+            cseq: 5,
+            data: FrameData::Identify {
+                name: "alice".to_string(),
+            },
+        };
+
+        assert_eq!(json.parse::<Frame>().unwrap(), expected_msg);
+    }
+
+    #[test]
+    fn presence_serialize() {
+        let frame = Frame::create_presence_frame("chat", vec!["alice".into(), "bob".into()]);
+        assert_eq!(
+            serde_json::to_string(&frame).unwrap(),
+            r#"{"cseq":0,"type":"presence","channel":"chat","members":["alice","bob"]}"#
+        );
+
+        let frame = Frame::create_presence_change_frame("chat", "alice", None, false);
+        assert_eq!(
+            serde_json::to_string(&frame).unwrap(),
+            r#"{"cseq":0,"type":"leave","channel":"chat","member":"alice"}"#
+        );
+
+        let frame = Frame::create_presence_change_frame("chat", "u42", Some("alice"), true);
+        assert_eq!(
+            serde_json::to_string(&frame).unwrap(),
+            r#"{"cseq":0,"type":"join","channel":"chat","member":"u42","name":"alice"}"#
+        );
+    }
+
     #[test]
     fn ready() {
         let frame = Frame {
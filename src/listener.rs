@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use crate::client::ClientAddr;
+
+/// Counter numbering connections accepted on Unix sockets
+static UNIX_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Address of listener
+///
+/// Parsed from `tcp://<host>:<port>` or `unix://<path>`, address without scheme is treated as TCP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        let addr = if let Some(path) = s.strip_prefix("unix://") {
+            ListenAddr::Unix(PathBuf::from(path))
+        } else {
+            ListenAddr::Tcp(s.strip_prefix("tcp://").unwrap_or(s).to_string())
+        };
+
+        match &addr {
+            ListenAddr::Tcp(host) if host.is_empty() || host.contains("://") => {
+                Err(anyhow!("Invalid listener address '{}'", s))
+            }
+            ListenAddr::Unix(path) if path.as_os_str().is_empty() => {
+                Err(anyhow!("Invalid listener address '{}'", s))
+            }
+            _ => Ok(addr),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "tcp://{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Parses comma separated list of listener addresses
+///
+/// # Arguments:
+/// * `addrs` - list of addresses, e.g. `tcp://127.0.0.1:8080,unix:///run/websocket.sock`
+pub fn parse_addrs(addrs: &str) -> Result<Vec<ListenAddr>> {
+    addrs
+        .split(',')
+        .filter(|addr| !addr.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Bound listener accepting client connections
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+/// Connection accepted by listener
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    /// Binds listener to address
+    ///
+    /// # Arguments:
+    /// * `addr` - listener address
+    pub async fn bind(addr: &ListenAddr) -> Result<Listener> {
+        let listener = match addr {
+            ListenAddr::Tcp(host) => Listener::Tcp(
+                TcpListener::bind(host.as_str())
+                    .await
+                    .with_context(|| format!("Failed to bind {}", addr))?,
+            ),
+            ListenAddr::Unix(path) => Listener::Unix(
+                UnixListener::bind(path).with_context(|| format!("Failed to bind {}", addr))?,
+                path.clone(),
+            ),
+        };
+
+        Ok(listener)
+    }
+
+    /// Waits for next connection
+    pub async fn accept(&mut self) -> io::Result<(Connection, ClientAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Connection::Tcp(stream), ClientAddr::Tcp(addr)))
+            }
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                let id = UNIX_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+                Ok((Connection::Unix(stream), ClientAddr::Unix(id)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "tcp://{}", addr),
+                Err(_) => write!(f, "tcp://<unknown>"),
+            },
+            Listener::Unix(_, path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+/// What to do after failed accept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptFailure {
+    /// Only the incoming connection failed, e.g. it was reset by peer
+    Connection,
+
+    /// Listener is temporarily out of resources, e.g. file descriptors
+    Resources,
+
+    /// Listener can't accept connections anymore
+    Fatal,
+}
+
+impl AcceptFailure {
+    /// Classifies error returned by `Listener::accept`
+    ///
+    /// # Arguments:
+    /// * `error` - accept error
+    pub fn of(error: &io::Error) -> AcceptFailure {
+        match error.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut => AcceptFailure::Connection,
+            // socket is not listening or is not a socket at all
+            io::ErrorKind::InvalidInput => AcceptFailure::Fatal,
+            // EMFILE, ENFILE, ENOBUFS, ENOMEM and the like
+            _ => AcceptFailure::Resources,
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // socket file would prevent binding the path on next start
+        if let Listener::Unix(_, path) = self {
+            if let Err(e) = std::fs::remove_file(&path) {
+                log::warn!("Failed to remove socket {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn listen_addrs() {
+        assert_eq!(
+            parse_addrs("tcp://127.0.0.1:8080, unix:///run/websocket.sock,0.0.0.0:80").unwrap(),
+            vec![
+                ListenAddr::Tcp("127.0.0.1:8080".into()),
+                ListenAddr::Unix("/run/websocket.sock".into()),
+                ListenAddr::Tcp("0.0.0.0:80".into()),
+            ]
+        );
+
+        assert!(parse_addrs("udp://127.0.0.1:8080").is_err());
+        assert!(parse_addrs("unix://").is_err());
+        assert!(parse_addrs("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn accept_failure() {
+        let error = |kind| io::Error::new(kind, "accept failed");
+
+        assert_eq!(
+            AcceptFailure::of(&error(io::ErrorKind::ConnectionAborted)),
+            AcceptFailure::Connection
+        );
+        assert_eq!(
+            AcceptFailure::of(&error(io::ErrorKind::InvalidInput)),
+            AcceptFailure::Fatal
+        );
+        // too many open files
+        assert_eq!(
+            AcceptFailure::of(&io::Error::from_raw_os_error(24)),
+            AcceptFailure::Resources
+        );
+    }
+}
@@ -1,16 +1,26 @@
 //#![deny(unused_imports, unused_must_use)]
 
 use std::env;
+use std::path::PathBuf;
 
 use anyhow::Result;
-use tokio::net::TcpListener;
+use config::{CliArgs, Config};
+use listener::Listener;
 
+pub mod acl;
 pub mod app;
+pub mod auth;
+pub mod backend;
 pub mod broker;
+pub mod cache;
 pub mod channel;
 pub mod client;
+pub mod config;
 pub mod frame;
+pub mod listener;
+pub mod outbox;
 pub mod state;
+pub mod tls;
 pub mod utils;
 
 #[tokio::main]
@@ -19,13 +29,26 @@ async fn main() -> Result<()> {
 
     dotenv::dotenv().ok();
 
-    let addr = env::var("SOCKET_ADDR").unwrap_or_else(|_| "127.0.0.1:8080".into());
-
-    // Create the event loop and TCP listener we'll accept connections on.
-    let listener = TcpListener::bind(&addr).await?;
-    log::info!("Listening on: {}", addr);
-
-    app::event_loop(listener).await?;
+    let args = CliArgs::parse(env::args().skip(1))?;
+    let config_path = args
+        .config
+        .or_else(|| env::var_os("CONFIG_PATH").map(PathBuf::from));
+    let config = Config::load(config_path.as_deref())?;
+
+    if args.check_config {
+        app::check_config(&config)?;
+        println!("Configuration is valid");
+        return Ok(());
+    }
+
+    // Create listeners we'll accept connections on.
+    let mut listeners = Vec::new();
+    for addr in &config.listeners {
+        listeners.push(Listener::bind(addr).await?);
+        log::info!("Listening on: {}", addr);
+    }
+
+    app::event_loop(listeners, config, config_path, utils::shutdown_signal()).await?;
 
     Ok(())
 }
@@ -0,0 +1,322 @@
+use crate::{client::ClientTx, frame::Frame, frame::FrameData};
+use anyhow::{anyhow, Result};
+use futures::SinkExt;
+use serde::{de, Deserialize, Deserializer};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message;
+
+/// What happens with frames of client, which doesn't keep up with reading them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Oldest queued frame is dropped, client detects gap in data frame versions and resyncs
+    DropOldest,
+
+    /// Queued data frames are replaced with a single snapshot of client's data
+    Coalesce,
+
+    /// Connection is closed
+    Disconnect,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "drop-oldest" => Ok(SlowConsumerPolicy::DropOldest),
+            "coalesce" => Ok(SlowConsumerPolicy::Coalesce),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            _ => Err(anyhow!(
+                "Unknown slow consumer policy '{}', expected drop-oldest, coalesce or disconnect",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SlowConsumerPolicy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Outbound queue settings
+#[derive(Debug, Clone, Copy)]
+pub struct OutboxConfig {
+    /// maximal number of queued frames
+    pub capacity: usize,
+
+    /// action taken when queue is full
+    pub policy: SlowConsumerPolicy,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            capacity: 64,
+            policy: SlowConsumerPolicy::Coalesce,
+        }
+    }
+}
+
+/// Item waiting for the writer
+#[derive(Debug)]
+enum Outgoing {
+    Frame(Frame),
+    Close(CloseFrame<'static>),
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    items: VecDeque<Outgoing>,
+    closed: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    notify: Notify,
+}
+
+/// Bounded queue of frames sent to client
+///
+/// Frames are written to websocket by a dedicated writer task, so a slow client never blocks
+/// the broker.
+#[derive(Debug)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+    capacity: usize,
+}
+
+impl Outbox {
+    /// Creates queue and spawns its writer task
+    ///
+    /// # Arguments:
+    /// * `tx` - websocket write half
+    /// * `capacity` - maximal number of queued frames
+    pub fn new(tx: ClientTx, capacity: usize) -> Outbox {
+        let shared = Arc::new(Shared::default());
+        tokio::spawn(write(Arc::clone(&shared), tx));
+
+        Outbox {
+            shared,
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Enqueues frame, gives it back if queue is full
+    ///
+    /// Fails if connection is closed or being closed
+    ///
+    /// # Arguments:
+    /// * `frame` - frame to be sent
+    pub fn push(&self, frame: Frame) -> Result<Option<Frame>> {
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        if queue.closed {
+            return Err(anyhow!("Connection is closed"));
+        }
+
+        if queue.items.len() >= self.capacity {
+            return Ok(Some(frame));
+        }
+
+        queue.items.push_back(Outgoing::Frame(frame));
+        self.shared.notify.notify();
+
+        Ok(None)
+    }
+
+    /// Enqueues frame regardless of capacity
+    ///
+    /// # Arguments:
+    /// * `frame` - frame to be sent
+    pub fn force_push(&self, frame: Frame) {
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        if !queue.closed {
+            queue.items.push_back(Outgoing::Frame(frame));
+            self.shared.notify.notify();
+        }
+    }
+
+    /// Drops the oldest queued frame
+    pub fn drop_oldest(&self) {
+        self.shared.queue.lock().unwrap().items.pop_front();
+    }
+
+    /// Drops all queued data frames, returns number of dropped frames
+    pub fn drop_data_frames(&self) -> usize {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let queued = queue.items.len();
+
+        queue.items.retain(|item| match item {
+            Outgoing::Frame(frame) => !matches!(frame.data(), FrameData::Data { .. }),
+            Outgoing::Close(_) => true,
+        });
+
+        queued - queue.items.len()
+    }
+
+    /// Checks if queue is full
+    pub fn is_full(&self) -> bool {
+        self.shared.queue.lock().unwrap().items.len() >= self.capacity
+    }
+
+    /// Sends close frame after already queued frames, nothing can be queued afterwards
+    ///
+    /// # Arguments:
+    /// * `close_frame` - close frame
+    /// * `discard` - drop queued frames, so close frame is sent right away
+    pub fn close(&self, close_frame: CloseFrame<'static>, discard: bool) {
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        if queue.closed {
+            return;
+        }
+
+        if discard {
+            queue.items.clear();
+        }
+
+        queue.items.push_back(Outgoing::Close(close_frame));
+        queue.closed = true;
+        self.shared.notify.notify();
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        // let the writer flush queued frames and finish
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.notify.notify();
+    }
+}
+
+/// Writer task, drains queue into websocket
+///
+/// # Arguments:
+/// * `shared` - queue shared with `Outbox`
+/// * `tx` - websocket write half
+async fn write(shared: Arc<Shared>, mut tx: ClientTx) {
+    loop {
+        let (item, closed) = {
+            let mut queue = shared.queue.lock().unwrap();
+            (queue.items.pop_front(), queue.closed)
+        };
+
+        let result = match item {
+            Some(Outgoing::Frame(frame)) => tx.send(frame.socket_msg()).await,
+            Some(Outgoing::Close(close_frame)) => {
+                if let Err(e) = tx.send(Message::Close(Some(close_frame))).await {
+                    log::debug!("Failed to send close frame: {}", e);
+                }
+                return;
+            }
+            None if closed => return,
+            None => {
+                shared.notify.notified().await;
+                continue;
+            }
+        };
+
+        if let Err(e) = result {
+            log::debug!("Failed to write to websocket: {}", e);
+
+            let mut queue = shared.queue.lock().unwrap();
+            queue.closed = true;
+            queue.items.clear();
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::{DeltaEncoding, DEFAULT_COMPRESSION_THRESHOLD};
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+    use futures::StreamExt;
+    use serde_json::json;
+    use tungstenite::Error as WsError;
+
+    fn sink() -> (ClientTx, UnboundedReceiver<Message>) {
+        let (tx, rx) = unbounded();
+        (Box::pin(tx.sink_map_err(|_| WsError::ConnectionClosed)), rx)
+    }
+
+    fn data_frame(version: u64) -> Frame {
+        Frame::create_push_frame(
+            DeltaEncoding::Snapshot,
+            version,
+            false,
+            json!({ "version": version }),
+            DEFAULT_COMPRESSION_THRESHOLD,
+        )
+    }
+
+    #[tokio::test]
+    async fn capacity() {
+        let (tx, rx) = sink();
+        let outbox = Outbox::new(tx, 2);
+
+        // writer task doesn't run until the test yields
+        assert_eq!(outbox.push(data_frame(1)).unwrap(), None);
+        assert_eq!(outbox.push(data_frame(2)).unwrap(), None);
+        assert!(outbox.is_full());
+        assert_eq!(outbox.push(data_frame(3)).unwrap(), Some(data_frame(3)));
+
+        drop(outbox);
+        let written: Vec<Message> = rx.collect().await;
+        assert_eq!(
+            written,
+            vec![data_frame(1).socket_msg(), data_frame(2).socket_msg()]
+        );
+    }
+
+    #[tokio::test]
+    async fn close() {
+        let (tx, rx) = sink();
+        let outbox = Outbox::new(tx, 2);
+        let close_frame = CloseFrame {
+            code: tungstenite::protocol::frame::coding::CloseCode::Away,
+            reason: "".into(),
+        };
+
+        outbox.push(data_frame(1)).unwrap();
+        outbox.close(close_frame.clone(), false);
+        assert!(outbox.push(data_frame(2)).is_err());
+
+        let written: Vec<Message> = rx.collect().await;
+        assert_eq!(
+            written,
+            vec![
+                data_frame(1).socket_msg(),
+                Message::Close(Some(close_frame))
+            ]
+        );
+    }
+
+    #[test]
+    fn slow_consumer_policy() {
+        assert_eq!(
+            "drop-oldest".parse::<SlowConsumerPolicy>().unwrap(),
+            SlowConsumerPolicy::DropOldest
+        );
+        assert_eq!(
+            "coalesce".parse::<SlowConsumerPolicy>().unwrap(),
+            SlowConsumerPolicy::Coalesce
+        );
+        assert_eq!(
+            "disconnect".parse::<SlowConsumerPolicy>().unwrap(),
+            SlowConsumerPolicy::Disconnect
+        );
+        assert!("block".parse::<SlowConsumerPolicy>().is_err());
+    }
+}
@@ -1,17 +1,18 @@
+use crate::backend::StateBackend;
 use serde_json::json;
 use serde_json::Value;
-use sqlx::SqlitePool;
+use std::sync::Arc;
 
 /// Dummy state
 pub struct State {
-    pub pool: SqlitePool,
+    pub backend: Arc<dyn StateBackend>,
     pub static_data: Value,
 }
 
 impl State {
-    pub fn new(pool: SqlitePool) -> State {
+    pub fn new(backend: Arc<dyn StateBackend>) -> State {
         State {
-            pool,
+            backend,
             static_data: json!({"version": "alpha"}),
         }
     }
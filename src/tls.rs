@@ -0,0 +1,61 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Creates TLS acceptor serving given certificate
+///
+/// # Arguments:
+/// * `cert_path` - path to PEM file with certificate chain
+/// * `key_path` - path to PEM file with private key, PKCS#8 or RSA
+pub fn acceptor<P: AsRef<Path>>(cert_path: P, key_path: P) -> Result<TlsAcceptor> {
+    let cert_chain = load_certs(cert_path.as_ref())?;
+    let key = load_key(key_path.as_ref())?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(cert_chain, key)
+        .context("Invalid TLS certificate or key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Reads certificate chain from PEM file
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = open(path)?;
+    let cert_chain =
+        certs(&mut reader).map_err(|_| anyhow!("Invalid certificate file {}", path.display()))?;
+
+    if cert_chain.is_empty() {
+        return Err(anyhow!("No certificates found in {}", path.display()));
+    }
+
+    Ok(cert_chain)
+}
+
+/// Reads first private key from PEM file
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let pkcs8_keys = pkcs8_private_keys(&mut open(path)?)
+        .map_err(|_| anyhow!("Invalid key file {}", path.display()))?;
+
+    let keys = if pkcs8_keys.is_empty() {
+        rsa_private_keys(&mut open(path)?)
+            .map_err(|_| anyhow!("Invalid key file {}", path.display()))?
+    } else {
+        pkcs8_keys
+    };
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No private key found in {}", path.display()))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+    Ok(BufReader::new(file))
+}
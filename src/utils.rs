@@ -1,6 +1,7 @@
 use anyhow::Result;
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
 use std::future::Future;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::task::JoinHandle;
 
 /// Spawns future in background and logs errors received from running tasks
@@ -9,7 +10,7 @@ use tokio::task::JoinHandle;
 /// * `fut` - a future with output `Result<()>`, threadsafe and borrowed forever
 pub fn spawn_and_log_err<F>(fut: F) -> JoinHandle<()>
 where
-    F: Future<Output = Result<()>> + Send +  'static,
+    F: Future<Output = Result<()>> + Send + 'static,
 {
     tokio::spawn(async move {
         if let Err(e) = fut.await {
@@ -18,84 +19,228 @@ where
     })
 }
 
+/// Resolves when process receives SIGINT or SIGTERM
+pub async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            log::error!("Failed to install SIGTERM handler: {}", e);
+
+            match tokio::signal::ctrl_c().await {
+                Ok(()) => log::info!("Received SIGINT"),
+                Err(e) => {
+                    // no way to request shutdown, keep serving
+                    log::error!("Failed to install SIGINT handler: {}", e);
+                    futures::future::pending::<()>().await;
+                }
+            }
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT"),
+        _ = sigterm.recv() => log::info!("Received SIGTERM"),
+    }
+}
+
+/// Key of the object marking removal of a key in incremental snapshot
+pub const DELETED_KEY: &str = "$deleted";
+
+/// Returns value which marks removed key in incremental snapshot - `{"$deleted": true}`
+pub fn deletion_marker() -> Value {
+    json!({ DELETED_KEY: true })
+}
+
+/// Checks if value marks removed key
+///
+/// # Arguments:
+/// * `value` - value from incremental snapshot
+pub fn is_deletion_marker(value: &Value) -> bool {
+    match value.as_object() {
+        Some(obj) => obj.len() == 1 && obj.get(DELETED_KEY) == Some(&Value::Bool(true)),
+        None => false,
+    }
+}
+
+/// Key of the object wrapping a value which replaces the old one as is
+pub const ESCAPED_KEY: &str = "$escaped";
+
+/// Checks if value would be taken for a marker, e.g. `{"$deleted": true}` stored by user
+///
+/// # Arguments:
+/// * `value` - value of new document
+fn is_reserved(value: &Value) -> bool {
+    match value.as_object() {
+        Some(obj) => {
+            obj.len() == 1 && (obj.contains_key(DELETED_KEY) || obj.contains_key(ESCAPED_KEY))
+        }
+        None => false,
+    }
+}
+
+/// Returns value wrapped with `escape` or `None` if value isn't escaped
+///
+/// # Arguments:
+/// * `value` - value from incremental snapshot
+fn unescape(value: &Value) -> Option<&Value> {
+    match value.as_object() {
+        Some(obj) if obj.len() == 1 => obj.get(ESCAPED_KEY),
+        _ => None,
+    }
+}
+
+/// Wraps value, so it's applied as is instead of being taken for a marker - `{"$escaped": value}`
+///
+/// # Arguments:
+/// * `value` - value of new document
+fn escape(value: &Value) -> Value {
+    json!({ ESCAPED_KEY: value })
+}
+
 /// Creates incremental diff of two json documents
 ///
+/// Objects are compared recursively, so only changed leaves are sent. Keys removed from
+/// the document are replaced with `deletion_marker()`, changed values which look like a marker
+/// are wrapped with `escape()`.
+///
 /// # Arguments:
 /// * `old_state` - old document (will be modified inplace)
 /// * `new_state` - new document
 pub fn create_json_snapshot(old_state: &mut Value, new_state: &Value) {
     // equal: just return
     if old_state == new_state {
-        std::mem::replace(old_state, json!({}));
+        *old_state = json!({});
         return;
     }
 
-    let old_state = old_state.as_object_mut().unwrap();
-    let new_state = new_state.as_object().unwrap();
+    diff_json(old_state, new_state);
+}
 
-    // remove keys
-    let to_remove = old_state
-        .keys()
-        .filter_map(|key| {
-            if !new_state.contains_key(key.as_str()) {
-                Some(key.to_string())
-            } else {
-                None
+/// Turns `old` into diff against `new`
+///
+/// Returns `false` if values are equal and diff should be omitted
+fn diff_json(old: &mut Value, new: &Value) -> bool {
+    let (old_dict, new_dict) = match (old.as_object_mut(), new.as_object()) {
+        (Some(old_dict), Some(new_dict)) => (old_dict, new_dict),
+        _ => {
+            if old == new {
+                return false;
             }
-        })
+
+            // replace old value with new value
+            *old = new.clone();
+            return true;
+        }
+    };
+
+    // mark removed keys
+    let to_remove = old_dict
+        .keys()
+        .filter(|key| !new_dict.contains_key(key.as_str()))
+        .cloned()
         .collect::<Vec<_>>();
 
     for key in to_remove {
-        old_state.remove(&key);
+        old_dict.insert(key, deletion_marker());
     }
 
-    for (channel, value) in new_state.iter() {
-        // iterate through keys and check if values should be removed/updated
-        let old_value = match old_state.get_mut(&*channel) {
-            Some(v) => v,
-            None => {
-                // insert new channel
-                old_state.insert(channel.clone(), value.clone());
-                continue;
+    for (key, new_val) in new_dict.iter() {
+        match old_dict.get_mut(key.as_str()) {
+            Some(old_val) => {
+                // remove equal values in order to reduce bandwidth
+                if !diff_json(old_val, new_val) {
+                    old_dict.remove(key.as_str());
+                } else if is_reserved(old_val) {
+                    *old_val = escape(new_val);
+                }
+            }
+            None if is_reserved(new_val) => {
+                old_dict.insert(key.clone(), escape(new_val));
             }
-        };
-
-        let new_dict = match value.as_object() {
-            Some(v) => v,
             None => {
-                // replace old value with new value
-                *old_value = value.clone();
-                continue;
+                // append value under key that occurred
+                old_dict.insert(key.clone(), new_val.clone());
             }
-        };
+        }
+    }
+
+    !old_dict.is_empty()
+}
 
-        let old_dict = match old_value.as_object_mut() {
-            Some(v) => v,
+/// Applies incremental diff created by `create_json_snapshot` - a client side counterpart
+///
+/// # Arguments:
+/// * `state` - document known by client (will be modified inplace)
+/// * `snapshot` - incremental diff received from server
+pub fn apply_json_snapshot(state: &mut Value, snapshot: &Value) {
+    let (state_dict, snapshot_dict) = match (state.as_object_mut(), snapshot.as_object()) {
+        (Some(state_dict), Some(snapshot_dict)) => (state_dict, snapshot_dict),
+        _ => {
+            *state = snapshot.clone();
+            return;
+        }
+    };
+
+    for (key, value) in snapshot_dict.iter() {
+        if is_deletion_marker(value) {
+            state_dict.remove(key.as_str());
+            continue;
+        }
+
+        if let Some(value) = unescape(value) {
+            state_dict.insert(key.clone(), value.clone());
+            continue;
+        }
+
+        match state_dict.get_mut(key.as_str()) {
+            Some(old_value) => apply_json_snapshot(old_value, value),
             None => {
-                // upgrade new value to dict
-                *old_value = Value::Object(new_dict.clone());
-                continue;
+                state_dict.insert(key.clone(), value.clone());
             }
-        };
+        }
+    }
+}
 
-        for (key, new_val) in new_dict.iter() {
-            match old_dict.get_mut(&*key) {
-                // remove equal values in order to reduce bandwidth
-                Some(old_v) if old_v == new_val => {
-                    old_dict.remove(&*key);
-                }
-                // update/append value under given key if changed/occurred
-                Some(_) | None => {
-                    old_dict.insert(key.clone(), new_val.clone());
-                }
+/// Creates JSON Merge Patch (RFC 7396) transforming `old_state` into `new_state`
+///
+/// # Arguments:
+/// * `old_state` - old document
+/// * `new_state` - new document
+pub fn create_merge_patch(old_state: &Value, new_state: &Value) -> Value {
+    let (old_dict, new_dict) = match (old_state.as_object(), new_state.as_object()) {
+        (Some(old_dict), Some(new_dict)) => (old_dict, new_dict),
+        _ => return new_state.clone(),
+    };
+
+    let mut patch = Map::new();
+
+    // removed keys are nulled
+    for key in old_dict.keys() {
+        if !new_dict.contains_key(key.as_str()) {
+            patch.insert(key.clone(), Value::Null);
+        }
+    }
+
+    for (key, new_val) in new_dict.iter() {
+        match old_dict.get(key.as_str()) {
+            Some(old_val) if old_val == new_val => {}
+            Some(old_val) => {
+                patch.insert(key.clone(), create_merge_patch(old_val, new_val));
+            }
+            None => {
+                patch.insert(key.clone(), new_val.clone());
             }
         }
     }
+
+    Value::Object(patch)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use proptest::prelude::*;
     use serde_json::json;
 
     #[test]
@@ -128,6 +273,130 @@ mod test {
         let mut json1 = json!({"channel_a": {"a": "xyz"}});
         let json2 = json!({"channel_b": {"a": "xyz"}});
         create_json_snapshot(&mut json1, &json2);
-        assert_eq!(json1, json2);
+        assert_eq!(
+            json1,
+            json!({"channel_a": deletion_marker(), "channel_b": {"a": "xyz"}})
+        );
+    }
+
+    #[test]
+    fn test_deep_patch() {
+        let mut json1 = json!({"channel": {"a": {"b": {"c": 1, "d": 2}}, "e": [1, 2]}});
+        let json2 = json!({"channel": {"a": {"b": {"c": 3, "d": 2}}, "e": [1, 2]}});
+        create_json_snapshot(&mut json1, &json2);
+        assert_eq!(json1, json!({"channel": {"a": {"b": {"c": 3}}}}));
+
+        let mut json1 = json!({"channel": {"a": {"b": 1, "c": 2}}});
+        let json2 = json!({"channel": {"a": {"b": 1}}});
+        create_json_snapshot(&mut json1, &json2);
+        assert_eq!(json1, json!({"channel": {"a": {"c": deletion_marker()}}}));
+    }
+
+    #[test]
+    fn test_apply_patch() {
+        let mut state = json!({"channel": {"a": {"b": 1, "c": 2}, "d": "xyz"}});
+        let snapshot = json!({"channel": {"a": {"c": deletion_marker(), "e": 3}, "d": {"f": 4}}});
+        apply_json_snapshot(&mut state, &snapshot);
+        assert_eq!(
+            state,
+            json!({"channel": {"a": {"b": 1, "e": 3}, "d": {"f": 4}}})
+        );
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let json1 = json!({"channel": {"a": {"b": 1, "c": 2}, "d": "xyz"}});
+        let json2 = json!({"channel": {"a": {"b": 1}, "d": {"e": 1}}});
+        assert_eq!(
+            create_merge_patch(&json1, &json2),
+            json!({"channel": {"a": {"c": null}, "d": {"e": 1}}})
+        );
+        assert_eq!(create_merge_patch(&json1, &json1), json!({}));
+    }
+
+    #[test]
+    fn test_reserved_values() {
+        let old = json!({"a": {"$deleted": false}, "b": 1, "d": {"$escaped": 1, "e": 2}});
+        let new = json!({
+            "a": {"$deleted": true},
+            "b": {"$deleted": true},
+            "c": {"$escaped": 1},
+            "d": {"$escaped": 2, "e": 2}
+        });
+
+        let mut snapshot = old.clone();
+        create_json_snapshot(&mut snapshot, &new);
+        assert_eq!(
+            snapshot,
+            json!({
+                "a": {"$escaped": {"$deleted": true}},
+                "b": {"$escaped": {"$deleted": true}},
+                "c": {"$escaped": {"$escaped": 1}},
+                "d": {"$escaped": {"$escaped": 2, "e": 2}}
+            })
+        );
+
+        let mut state = old;
+        apply_json_snapshot(&mut state, &snapshot);
+        assert_eq!(state, new);
+    }
+
+    fn arb_scalar() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            "[a-z]{0,3}".prop_map(Value::from),
+        ]
+    }
+
+    fn arb_json() -> impl Strategy<Value = Value> {
+        arb_tree(prop_oneof![Just(Value::Null), arb_scalar()])
+    }
+
+    /// merge patch can't express null values
+    fn arb_json_without_null() -> impl Strategy<Value = Value> {
+        arb_tree(arb_scalar())
+    }
+
+    fn arb_tree(leaf: impl Strategy<Value = Value> + 'static) -> impl Strategy<Value = Value> {
+        leaf.prop_recursive(4, 32, 4, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..4).prop_map(Value::from),
+                arb_object(inner),
+            ]
+        })
+    }
+
+    fn arb_object(values: impl Strategy<Value = Value>) -> impl Strategy<Value = Value> {
+        // narrow key space, so generated documents share keys
+        // reserved keys of incremental snapshots included
+        prop::collection::btree_map("[a-c]{1,2}|\\$deleted|\\$escaped", values, 0..4)
+            .prop_map(|map| Value::Object(map.into_iter().collect()))
+    }
+
+    proptest! {
+        #[test]
+        fn snapshot_round_trip(old in arb_object(arb_json()), new in arb_object(arb_json())) {
+            let mut snapshot = old.clone();
+            create_json_snapshot(&mut snapshot, &new);
+
+            let mut state = old;
+            apply_json_snapshot(&mut state, &snapshot);
+
+            prop_assert_eq!(state, new);
+        }
+
+        #[test]
+        fn merge_patch_round_trip(
+            old in arb_object(arb_json_without_null()),
+            new in arb_object(arb_json_without_null())
+        ) {
+            let patch = create_merge_patch(&old, &new);
+
+            let mut state = old;
+            json_patch::merge(&mut state, &patch);
+
+            prop_assert_eq!(state, new);
+        }
     }
 }